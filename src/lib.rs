@@ -7,6 +7,7 @@
 #![feature(panic_handler)]
 #![feature(alloc_error_handler)]
 #![feature(asm)]
+#![feature(try_from)]
 #![no_std]
 #![cfg_attr(not(test), no_main)]
 #![cfg_attr(test, allow(dead_code, unused_macros, unused_imports, unused_attributes))]
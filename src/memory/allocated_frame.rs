@@ -0,0 +1,76 @@
+use core::ops::Deref;
+
+use super::{Frame, FrameDeallocator};
+
+/// Frame which is freed again when the guard is dropped, unless it is leaked
+pub struct AllocatedFrame<'a, A: FrameDeallocator + ?Sized + 'a> {
+    frame: Option<Frame>,
+    allocator: &'a mut A,
+}
+
+impl<'a, A: FrameDeallocator + ?Sized + 'a> AllocatedFrame<'a, A> {
+    pub fn new(frame: Frame, allocator: &'a mut A) -> AllocatedFrame<'a, A> {
+        AllocatedFrame {
+            frame: Some(frame),
+            allocator: allocator,
+        }
+    }
+
+    /// Gives up ownership of the frame without freeing it, e.g. when it
+    /// is stored in a page table
+    pub fn leak(mut self) -> Frame {
+        self.frame.take().unwrap()
+    }
+}
+
+impl<'a, A: FrameDeallocator + ?Sized + 'a> Deref for AllocatedFrame<'a, A> {
+    type Target = Frame;
+
+    fn deref(&self) -> &Frame {
+        self.frame.as_ref().unwrap()
+    }
+}
+
+impl<'a, A: FrameDeallocator + ?Sized + 'a> Drop for AllocatedFrame<'a, A> {
+    fn drop(&mut self) {
+        if let Some(frame) = self.frame.take() {
+            self.allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::{FrameManager, MockAllocator};
+
+    #[test]
+    fn dropping_guard_frees_frame() {
+        let mut allocator = MockAllocator::new(4);
+        {
+            let frame = allocator.allocate_frame_owned().unwrap();
+            assert_eq!(frame.number(), 0);
+            assert_eq!(frame.allocator.used_count(), 1);
+        }
+        assert_eq!(allocator.used_count(), 0);
+    }
+
+    #[test]
+    fn leaked_frame_stays_allocated() {
+        let mut allocator = MockAllocator::new(4);
+        let frame = allocator.allocate_frame_owned().unwrap().leak();
+        assert_eq!(frame.number(), 0);
+        assert_eq!(allocator.used_count(), 1);
+    }
+
+    #[test]
+    fn guard_works_with_trait_objects() {
+        let mut allocator = MockAllocator::new(4);
+        {
+            let mut dyn_allocator: &mut dyn FrameManager = &mut allocator;
+            let frame = FrameManager::allocate_frame_owned(&mut dyn_allocator).unwrap();
+            assert_eq!(frame.start_address(), 0);
+        }
+        assert_eq!(allocator.used_count(), 0);
+    }
+}
@@ -1,147 +1,4078 @@
 use core;
+use core::fmt;
 use core::mem;
+#[cfg(test)]
+use core::cell::Cell;
+use core::ptr;
 
-use memory::paging::PAGE_SIZE;
-use super::{Frame, FrameAllocator};
-use multiboot2::MemoryAreaIter;
+use memory::paging::{PAGE_SIZE, PhysicalAddress};
+use super::{Frame, HugeFrame, NonZeroFrame, FrameRange, FrameRangeInclusive, FrameAllocator, FrameDeallocator, FrameAllocError, align_up};
+use multiboot2::{MemoryAreaIter, ElfSectionIter};
 
 const MAX_MEM_SIZE: usize = 4294967296;
 const NUM_OF_FRAMES: usize = MAX_MEM_SIZE/PAGE_SIZE;
 const BITS_PER_BLOCK: usize = mem::size_of::<usize>() * 8;
 const ARRAY_SIZE: usize = NUM_OF_FRAMES/BITS_PER_BLOCK;
+const HUGE_FRAME_SIZE: usize = 2 * 1024 * 1024;
+const FRAMES_PER_HUGE_FRAME: usize = HUGE_FRAME_SIZE/PAGE_SIZE;
+const BLOCKS_PER_HUGE_FRAME: usize = FRAMES_PER_HUGE_FRAME/BITS_PER_BLOCK;
+/// Frames which devices limited to 32 bit addresses can reach
+const DMA32_FRAMES: usize = 0x1_0000_0000/PAGE_SIZE;
+/// Most frames an `AllocateIter` can hand out, enough to fill a page table
+pub const ALLOCATE_ITER_CAPACITY: usize = 512;
 
+/// Most cache colors `allocate_frame_with_color` keeps cursors for
+pub const MAX_COLORS: usize = 64;
+
+/// Backing store of the global allocator. Allocators manage as much memory
+/// as their bitmap slice can track, so a bigger array covers more memory.
 pub static mut BITMAP: [usize; ARRAY_SIZE] = [0; ARRAY_SIZE];
+/// Reserved frames of the global allocator
+pub static mut RESERVED: [usize; ARRAY_SIZE] = [0; ARRAY_SIZE];
+
+/// Rounds a frame number up to a multiple of `align`, which need not be a power of two
+fn round_up(number: usize, align: usize) -> usize {
+    (number + align - 1) / align * align
+}
+
+/// Mask of `bits` consecutive bits starting at bit `offset` of a block
+fn block_mask(offset: usize, bits: usize) -> usize {
+    if bits == BITS_PER_BLOCK {
+        core::usize::MAX
+    } else {
+        ((1usize << bits) - 1) << offset
+    }
+}
+
+fn set_bit(bitmap: &mut [usize], index: usize, value: bool) {
+    if value {
+        bitmap[index / BITS_PER_BLOCK] |= 1usize << (index % BITS_PER_BLOCK);
+    } else {
+        bitmap[index / BITS_PER_BLOCK] &= !(1usize << (index % BITS_PER_BLOCK));
+    }
+}
+
+fn bit_is_set(bitmap: &[usize], index: usize) -> bool {
+    (bitmap[index / BITS_PER_BLOCK] & (1usize << (index % BITS_PER_BLOCK))) != 0
+}
+
+/// First frame number at or after `frame_number` which has the given color
+fn next_frame_with_color(frame_number: usize, color: usize, num_colors: usize) -> usize {
+    frame_number + (color + num_colors - frame_number % num_colors) % num_colors
+}
+
+/// Adds a free run to the bucket of its length's power of two, the last
+/// bucket also takes longer runs
+fn count_free_run(buckets: &mut [usize; 32], run_length: usize) {
+    if run_length > 0 {
+        let log2 = BITS_PER_BLOCK - 1 - run_length.leading_zeros() as usize;
+        buckets[core::cmp::min(log2, buckets.len() - 1)] += 1;
+    }
+}
+
+/// Zeroes a frame through the identity mapping of physical memory,
+/// which only exists during early boot
+fn zero_frame_identity(frame: &Frame) {
+    unsafe {
+        ptr::write_bytes(frame.as_ptr_identity(), 0, PAGE_SIZE);
+    }
+}
+
+/// Start and end address of the kernel image, given the `(addr, size)` of
+/// every section which is loaded into memory
+pub fn kernel_bounds<I>(sections: I) -> Option<(usize, usize)>
+    where I: Iterator<Item = (usize, usize)>
+{
+    sections.fold(None, |bounds, (addr, size)| match bounds {
+        Some((start, end)) => Some((core::cmp::min(start, addr), core::cmp::max(end, addr + size))),
+        None => Some((addr, addr + size)),
+    })
+}
+
+/// Where `allocate_frame_with_hint` should look for a free frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocHint {
+    /// Wherever `allocate_frame` would allocate
+    Any,
+    /// The lowest free frame
+    PreferLow,
+    /// The highest free frame
+    PreferHigh,
+    /// A frame starting below the given address, or none at all
+    Below(usize),
+}
+
+/// How `allocate_frame` and `allocate_contiguous_frames` pick free frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// The lowest free frames
+    FirstFit,
+    /// The first free frames after the previous allocation
+    NextFit,
+    /// The smallest free run which is large enough. Single frames are
+    /// allocated first-fit.
+    BestFit,
+}
+
+/// Which end of memory `allocate_frame` takes frames from first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanDirection {
+    /// Low frames first
+    Up,
+    /// High frames first, keeping low memory free for devices which need it
+    Down,
+}
+
+/// Details of a failed allocation, see `last_failure`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastFailure {
+    pub kind: FrameAllocError,
+    /// Number of frames requested
+    pub requested: usize,
+    pub free_at_time: usize,
+    /// Frame number `allocate_frame` would have continued at
+    pub cursor: usize,
+}
+
+/// What the allocator was asked for when it ran out of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OomInfo {
+    /// Number of frames requested
+    pub requested: usize,
+    pub free_frames: usize,
+    pub hint: AllocHint,
+}
+
+/// Passed to the trace function installed with `set_trace`
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameEvent {
+    Allocated(Frame),
+    Deallocated(Frame),
+}
+
+/// What to do after the out of memory handler ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    Fail,
+    /// The handler freed memory, so the allocation is tried once more
+    Retry,
+}
+
+/// Why `serialize_into` or `deserialize_from` failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializeError {
+    /// The output buffer or the bitmaps to deserialize into are too small
+    BufferTooSmall,
+    /// The bytes do not start with `SERIALIZE_MAGIC`
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// Serialized on a machine with words of the given number of bytes
+    WordSizeMismatch(u8),
+    /// The bytes end in the middle of the state
+    Truncated,
+    /// The bitmaps do not match the header
+    Corrupt,
+}
+
+/// First bytes of the output of `serialize_into`
+pub const SERIALIZE_MAGIC: [u8; 4] = *b"BFAS";
+const SERIALIZE_VERSION: u8 = 1;
+const WORD_BYTES: usize = mem::size_of::<usize>();
+/// Size of the repeat count of a run of equal bitmap words
+const RUN_LENGTH_BYTES: usize = 4;
+
+/// Writes little endian integers into a byte buffer
+struct ByteWriter<'b> {
+    bytes: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> ByteWriter<'b> {
+    fn put(&mut self, value: usize, size: usize) -> Result<(), SerializeError> {
+        if self.bytes.len() - self.len < size {
+            return Err(SerializeError::BufferTooSmall);
+        }
+        for i in 0..size {
+            self.bytes[self.len + i] = (value >> (8 * i)) as u8;
+        }
+        self.len += size;
+        Ok(())
+    }
+
+    /// Each run of equal words as its length followed by the word
+    fn put_runs(&mut self, words: &[usize]) -> Result<(), SerializeError> {
+        let max_run = (1 << (8 * RUN_LENGTH_BYTES)) - 1;
+        let mut i = 0;
+        while i < words.len() {
+            let word = words[i];
+            let run = words[i..].iter().take(max_run).take_while(|&&other| other == word).count();
+            self.put(run, RUN_LENGTH_BYTES)?;
+            self.put(word, WORD_BYTES)?;
+            i += run;
+        }
+        Ok(())
+    }
+}
+
+/// Reads what a `ByteWriter` wrote
+struct ByteReader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> ByteReader<'b> {
+    fn get(&mut self, size: usize) -> Result<usize, SerializeError> {
+        if self.bytes.len() - self.pos < size {
+            return Err(SerializeError::Truncated);
+        }
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.bytes[self.pos + i] as usize) << (8 * i);
+        }
+        self.pos += size;
+        Ok(value)
+    }
+
+    /// Fills `words` from runs written by `put_runs`
+    fn get_runs(&mut self, words: &mut [usize]) -> Result<(), SerializeError> {
+        let mut i = 0;
+        while i < words.len() {
+            let run = self.get(RUN_LENGTH_BYTES)?;
+            let word = self.get(WORD_BYTES)?;
+            if run == 0 || run > words.len() - i {
+                return Err(SerializeError::Corrupt);
+            }
+            for other in &mut words[i..i + run] {
+                *other = word;
+            }
+            i += run;
+        }
+        Ok(())
+    }
+}
 
 pub struct BitmapFrameAllocator<'a> {
     bitmap: &'a mut [usize],
+    /// Frames which are used and may only be freed with `release_range`.
+    /// Reserved frames are always marked in `bitmap` as well, so searches only look there.
+    reserved: &'a mut [usize],
+    /// Frame tracked by the first bit of `bitmap`. A multiple of the frames per
+    /// bitmap block, so frame numbers and bit indices share their offset in a block.
+    base_frame: usize,
     second_scan: bool,
     next_frame: Frame,
     last_frame: Frame,
+    /// Every frame below it is used
+    low_frame: Frame,
+    /// Every managed frame at or above it is used
+    high_frame: Frame,
+    /// Every frame below it is used, DMA32 allocations start looking here
+    dma32_frame: Frame,
+    /// Where `allocate_frame_with_color` continues looking for each of `color_count` colors
+    color_cursors: [usize; MAX_COLORS],
+    color_count: usize,
+    /// Lowest frame freed since `allocate_frame` last looked, `usize::MAX` if none
+    lowest_free_hint: usize,
+    zeroer: fn(&Frame),
+    zeroed: Option<&'a mut [usize]>,
+    zero_on_alloc: bool,
+    /// References to every managed frame, see `track_refcounts`
+    refcounts: Option<&'a mut [u8]>,
+    free_frames: usize,
+    total_frames: usize,
+    /// Free frames which only `allocate_frame_critical` may take
+    reserve: usize,
+    oom_handler: Option<fn(&mut BitmapFrameAllocator<'a>, &OomInfo) -> OomAction>,
+    trace: Option<fn(FrameEvent)>,
+    /// Threshold and callback installed with `set_low_watermark`
+    low_watermark: Option<(usize, fn())>,
+    /// Set while free memory is above the watermark, so the callback fires once per drop
+    low_watermark_armed: bool,
+    /// Frame 0 was free before it was reserved, see `release_null_frame`
+    null_frame_reserved: bool,
+    policy: AllocPolicy,
+    /// `allocate_frame` does not go back to frames freed below the cursor, see `set_rotate`
+    rotate: bool,
+    direction: ScanDirection,
+    last_failure: Option<LastFailure>,
+    /// Bitmap blocks read by single frame and contiguous searches
+    #[cfg(test)]
+    scanned_blocks: Cell<usize>,
 }
 
-impl<'a> FrameAllocator for BitmapFrameAllocator<'a> {
-    fn allocate_frame(&mut self) -> Option<Frame> {
-        loop {
-            match self.next_frame >= self.last_frame {
-                false => {
-                    let block_number = BitmapFrameAllocator::get_block_number(self.next_frame.number());
-                    let frame = self.find_free_frame_in_block(block_number);
-                    if frame.is_some() {
-                        return frame
-                    }
-                },
-                true if !self.second_scan => {
-                    self.second_scan = true;
-                    self.next_frame = Frame{ number: 0 };
-                },
-                true => {
-                    self.second_scan = false;
-                    return None
+/// Shows the cursor and frame counts instead of the whole bitmap
+impl<'a> fmt::Debug for BitmapFrameAllocator<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BitmapFrameAllocator")
+            .field("next_frame", &self.next_frame)
+            .field("last_frame", &self.last_frame)
+            .field("second_scan", &self.second_scan)
+            .field("free_frames", &self.free_frames)
+            .field("used_frames", &self.used_frame_count())
+            .finish()
+    }
+}
+
+/// Iterator over the free frames of a `BitmapFrameAllocator`
+pub struct FreeFrameIter<'a> {
+    bitmap: &'a [usize],
+    base_frame: usize,
+    next_frame: usize,
+    last_frame: usize,
+}
+
+impl<'a> Iterator for FreeFrameIter<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        while self.next_frame < self.last_frame {
+            let block_number = (self.next_frame - self.base_frame) / BITS_PER_BLOCK;
+            let block = self.bitmap[block_number];
+            let free_bits = !block & (core::usize::MAX << (self.next_frame % BITS_PER_BLOCK));
+            if block == core::usize::MAX || free_bits == 0 {
+                self.next_frame = self.base_frame + (block_number + 1) * BITS_PER_BLOCK;
+            } else {
+                let frame_number = self.base_frame + block_number * BITS_PER_BLOCK
+                                   + free_bits.trailing_zeros() as usize;
+                self.next_frame = frame_number + 1;
+                if frame_number < self.last_frame {
+                    return Some(Frame::from_number(frame_number))
                 }
             }
         }
+        None
+    }
+}
+
+/// Iterator which allocates a frame on every call to `next`
+pub struct AllocateIter<'b, 'a: 'b> {
+    allocator: &'b mut BitmapFrameAllocator<'a>,
+    remaining: usize,
+    exhausted: bool,
+    yielded: [usize; ALLOCATE_ITER_CAPACITY],
+    yielded_count: usize,
+}
+
+impl<'b, 'a> AllocateIter<'b, 'a> {
+    /// Did the iterator stop because memory ran out?
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// Frees every frame the iterator has handed out, which must not be used afterwards
+    pub fn rollback(self) {
+        for &frame_number in &self.yielded[..self.yielded_count] {
+            self.allocator.deallocate_frame(Frame::from_number(frame_number));
+        }
+    }
+}
+
+impl<'b, 'a> Iterator for AllocateIter<'b, 'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.remaining == 0 || self.exhausted {
+            return None
+        }
+        match self.allocator.allocate_frame() {
+            Some(frame) => {
+                self.yielded[self.yielded_count] = frame.number();
+                self.yielded_count += 1;
+                self.remaining -= 1;
+                Some(frame)
+            },
+            None => {
+                self.exhausted = true;
+                None
+            }
+        }
     }
+}
+
+impl<'a> FrameAllocator for BitmapFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_run_checked(1, AllocHint::Any, false, |allocator| {
+            allocator.allocate_frame_by_policy().ok_or(FrameAllocError::OutOfMemory)
+        }).ok()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(count, AllocHint::Any, false, |allocator| allocator.take_run_by_policy(count))
+    }
+}
 
+impl<'a> FrameDeallocator for BitmapFrameAllocator<'a> {
     fn deallocate_frame(&mut self, frame: Frame) {
-        debug_assert!(frame < self.last_frame);
-        self.set_used(frame.number(), false);
+        assert!(self.frame_in_range(frame.number()), "{} exceeds managed memory", frame);
+        let result = self.try_deallocate_frame(frame);
+        assert!(result.is_ok(), "frame deallocation failed: {:?}", result);
     }
 }
 
 impl<'a> BitmapFrameAllocator<'a> {
-    pub fn new(bitmap: &'a mut [usize], kernel_start: usize, kernel_end: usize, 
-               multiboot_start: usize, multiboot_end: usize, 
-               memory_areas: MemoryAreaIter) -> BitmapFrameAllocator 
+    /// `reserved` must be at least as long as `bitmap`
+    pub fn new(bitmap: &'a mut [usize], reserved: &'a mut [usize], kernel_start: PhysicalAddress, kernel_end: PhysicalAddress, 
+               multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress, 
+               memory_areas: MemoryAreaIter) -> BitmapFrameAllocator<'a>
+    {
+        match BitmapFrameAllocator::try_new(bitmap, reserved, kernel_start, kernel_end, multiboot_start, multiboot_end,
+                                            memory_areas) {
+            Ok(allocator) => allocator,
+            Err(FrameAllocError::InvalidRange) => panic!("kernel {:#x}..={:#x} or multiboot structure {:#x}..={:#x} \
+                                                          lies outside of managed memory",
+                                                         kernel_start.as_usize(), kernel_end.as_usize(),
+                                                         multiboot_start.as_usize(), multiboot_end.as_usize()),
+            Err(_) => panic!("memory map contains no usable memory"),
+        }
+    }
+
+    /// Like `new`, but returns `NoMemoryMap` instead of panicking if there are no memory areas,
+    /// and `InvalidRange` if the kernel or the multiboot structure lies above managed memory
+    pub fn try_new(bitmap: &'a mut [usize], reserved: &'a mut [usize], kernel_start: PhysicalAddress, kernel_end: PhysicalAddress, 
+                   multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress, 
+                   memory_areas: MemoryAreaIter) -> Result<BitmapFrameAllocator<'a>, FrameAllocError>
+    {
+        let memory_areas = memory_areas.map(|area| (area.base_addr as usize, area.base_addr.saturating_add(area.length) as usize));
+        BitmapFrameAllocator::from_areas(bitmap, reserved, kernel_start, kernel_end, multiboot_start, multiboot_end,
+                                         memory_areas)
+    }
+
+    /// Like `try_new`, but takes the kernel bounds from the ELF sections
+    /// the bootloader loaded
+    pub fn from_elf_sections(bitmap: &'a mut [usize], reserved: &'a mut [usize], elf_sections: ElfSectionIter,
+                             multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                             memory_areas: MemoryAreaIter) -> Result<BitmapFrameAllocator<'a>, FrameAllocError>
     {
-        let mut allocator = BitmapFrameAllocator {
+        let sections = elf_sections.filter(|section| section.is_allocated())
+                                   .map(|section| (section.addr as usize, section.size as usize));
+        let (kernel_start, kernel_end) = kernel_bounds(sections).ok_or(FrameAllocError::NoKernelSections)?;
+        BitmapFrameAllocator::try_new(bitmap, reserved, PhysicalAddress::new(kernel_start), PhysicalAddress::new(kernel_end),
+                                      multiboot_start, multiboot_end, memory_areas)
+    }
+
+    /// Builds the allocator from `(start, end)` address pairs of memory areas
+    /// which are already filtered to usable RAM, e.g. by boot code which reads
+    /// the memory map itself. Every frame starts out used and only frames inside
+    /// the areas are freed. The areas may come in any order and are walked only once.
+    pub fn from_areas<I>(bitmap: &'a mut [usize], reserved: &'a mut [usize], kernel_start: PhysicalAddress,
+                         kernel_end: PhysicalAddress, multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                     memory_areas: I) -> Result<BitmapFrameAllocator<'a>, FrameAllocError>
+        where I: Iterator<Item = (usize, usize)>
+    {
+        BitmapFrameAllocator::from_areas_with_base(bitmap, reserved, Frame::from_number(0), kernel_start, kernel_end,
+                                                   multiboot_start, multiboot_end, memory_areas)
+    }
+
+    /// Like `from_areas`, but the bitmap starts at `base_frame` instead of frame 0,
+    /// e.g. to leave a reserved low region out. Memory below it is never used.
+    /// `base_frame` has to be a multiple of the number of bits in a `usize`.
+    pub fn from_areas_with_base<I>(bitmap: &'a mut [usize], reserved: &'a mut [usize], base_frame: Frame,
+                                   kernel_start: PhysicalAddress, kernel_end: PhysicalAddress,
+                                   multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                                   memory_areas: I) -> Result<BitmapFrameAllocator<'a>, FrameAllocError>
+        where I: Iterator<Item = (usize, usize)>
+    {
+        assert!(reserved.len() >= bitmap.len(), "Bitmap of reserved frames is too small");
+        assert!(base_frame.number() % BITS_PER_BLOCK == 0, "{} does not start a bitmap block", base_frame);
+        let mut allocator = BitmapFrameAllocator::empty(bitmap, reserved, base_frame);
+        allocator.map_memory_areas(memory_areas)?;
+        let kernel = FrameRange::containing(kernel_start, kernel_end);
+        let multiboot = FrameRange::containing(multiboot_start, multiboot_end);
+        // a kernel above the top of memory means the linker script or the bootloader is off
+        if kernel.end > allocator.last_frame || multiboot.end > allocator.last_frame {
+            return Err(FrameAllocError::InvalidRange)
+        }
+        allocator.map_kernel(&kernel);
+        allocator.map_multiboot(&multiboot, &kernel);
+        // frame 0 holds the real mode IVT and looks like a null pointer, so it is
+        // always reserved, whatever the memory map says
+        let null_frame = PhysicalAddress::new(0);
+        allocator.null_frame_reserved = allocator.reserve_region(null_frame, null_frame) == 1;
+        Ok(allocator)
+    }
+
+    /// Allocator which manages no memory yet, the caller sets up the bitmap and `last_frame`
+    fn empty(bitmap: &'a mut [usize], reserved: &'a mut [usize], base_frame: Frame) -> BitmapFrameAllocator<'a> {
+        let base = base_frame.number();
+        for block in reserved.iter_mut() {
+            *block = 0;
+        }
+        BitmapFrameAllocator {
             bitmap: bitmap,
+            reserved: reserved,
+            base_frame: base,
             second_scan: false,
-            next_frame: Frame::containing_address(0),
-            last_frame: Frame::containing_address(0),
+            next_frame: Frame::from_number(base),
+            last_frame: Frame::from_number(base),
+            low_frame: Frame::from_number(base),
+            high_frame: Frame::from_number(base),
+            dma32_frame: Frame::from_number(base),
+            color_cursors: [base; MAX_COLORS],
+            color_count: 0,
+            lowest_free_hint: core::usize::MAX,
+            zeroer: zero_frame_identity,
+            zeroed: None,
+            zero_on_alloc: false,
+            refcounts: None,
+            free_frames: 0,
+            total_frames: 0,
+            reserve: 0,
+            oom_handler: None,
+            trace: None,
+            low_watermark: None,
+            low_watermark_armed: true,
+            null_frame_reserved: false,
+            policy: AllocPolicy::NextFit,
+            rotate: false,
+            direction: ScanDirection::Up,
+            last_failure: None,
+            #[cfg(test)]
+            scanned_blocks: Cell::new(0),
+        }
+    }
+
+    /// Allocates `count` contiguous frames, either all of them or none.
+    /// A single frame is allocated exactly like `allocate_frame` does.
+    pub fn allocate_frames(&mut self, count: usize) -> Result<FrameRangeInclusive, FrameAllocError> {
+        if count == 1 {
+            return self.try_allocate_frame().map(|frame| Frame::range_inclusive(frame, frame));
+        }
+
+        let start = self.allocate_contiguous_frames(count)?;
+        let end = Frame::from_number(start.number() + count - 1);
+        Ok(Frame::range_inclusive(start, end))
+    }
+
+    /// Like `allocate_frames`, but the number of the first frame is a multiple
+    /// of `align_frames`, which has to be a power of two
+    pub fn allocate_frames_aligned(&mut self, count: usize, align_frames: usize) -> Result<FrameRangeInclusive, FrameAllocError> {
+        if !align_frames.is_power_of_two() {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        let start = self.allocate_contiguous_aligned(count, align_frames)?;
+        let end = Frame::from_number(start.number() + count - 1);
+        Ok(Frame::range_inclusive(start, end))
+    }
+
+    /// Allocates `count` contiguous frames, the first of which has a number
+    /// that is a multiple of `align_frames`
+    pub fn allocate_contiguous_aligned(&mut self, count: usize, align_frames: usize) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(count, AllocHint::Any, false, |allocator| allocator.take_free_run(count, align_frames))
+    }
+
+    fn take_free_run(&mut self, count: usize, align_frames: usize) -> Result<Frame, FrameAllocError> {
+        if count == 0 || align_frames == 0 {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        let first_frame_number = self.find_free_run(count, align_frames).ok_or(FrameAllocError::OutOfMemory)?;
+        for frame_number in first_frame_number..first_frame_number + count {
+            self.set_used(frame_number, true);
+        }
+        Ok(Frame::from_number(first_frame_number))
+    }
+
+    /// Allocates `count` contiguous frames from the smallest free run which is
+    /// large enough, or the lowest of several equally small ones
+    pub fn allocate_frames_best_fit(&mut self, count: usize) -> Result<FrameRangeInclusive, FrameAllocError> {
+        let start = self.allocate_run_checked(count, AllocHint::Any, false,
+                                              |allocator| allocator.take_best_fit_run(count))?;
+        let end = Frame::from_number(start.number() + count - 1);
+        Ok(Frame::range_inclusive(start, end))
+    }
+
+    /// Pins a contiguous region for the kernel heap, meant to be called right
+    /// after `new` while memory is not fragmented yet. On `None` no frame was taken.
+    pub fn reserve_heap(&mut self, frames: usize) -> Option<FrameRange> {
+        let start = self.allocate_contiguous_frames(frames).ok()?;
+        Some(Frame::range(start, start + frames))
+    }
+
+    fn take_best_fit_run(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        let first_frame_number = self.find_best_fit_run(count).ok_or(FrameAllocError::OutOfMemory)?;
+        for frame_number in first_frame_number..first_frame_number + count {
+            self.set_used(frame_number, true);
+        }
+        Ok(Frame::from_number(first_frame_number))
+    }
+
+    /// Allocates a frame whose start address is below `limit`, e.g. for ISA DMA.
+    /// The cursor used by `allocate_frame` is left untouched.
+    pub fn allocate_frame_below(&mut self, limit: usize) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(1, AllocHint::Below(limit), false, |allocator| allocator.take_frame_below(limit))
+    }
+
+    fn take_frame_below(&mut self, limit: usize) -> Result<Frame, FrameAllocError> {
+        let end = limit / PAGE_SIZE + if limit % PAGE_SIZE != 0 { 1 } else { 0 };
+        let start = Frame::from_number(self.base_frame);
+        self.take_frame_between(start, Frame::from_number(end))
+    }
+
+    /// Allocates a frame from `[start, end)`.
+    /// The cursor used by `allocate_frame` is left untouched.
+    pub fn allocate_frame_in_range(&mut self, start: Frame, end: Frame) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(1, AllocHint::Any, false, |allocator| allocator.take_frame_between(start, end))
+    }
+
+    /// Allocates a frame from `[min, max]`, e.g. below the 16 MiB ISA DMA limit
+    /// with `max` being the frame containing 0xffffff. `None` if `min > max`,
+    /// or if `max` is the highest frame number, which has no frame past it.
+    pub fn allocate_frame_in_range_inclusive(&mut self, min: Frame, max: Frame) -> Option<Frame> {
+        if min > max {
+            return None
+        }
+        let end = Frame::from_number(max.number().checked_add(1)?);
+        self.allocate_frame_in_range(min, end).ok()
+    }
+
+    fn take_frame_between(&mut self, start: Frame, end: Frame) -> Result<Frame, FrameAllocError> {
+        if start >= end || !self.frame_in_range(start.number()) {
+            return Err(FrameAllocError::InvalidRange)
+        }
+        let frame_number = self.find_free_frame_between(start.number(), end.number())
+                               .ok_or(FrameAllocError::OutOfMemory)?;
+        self.set_used(frame_number, true);
+        Ok(Frame::from_number(frame_number))
+    }
+
+    /// Allocates a frame below 4 GiB for devices which can only address 32 bits.
+    /// Keeps its own cursor, so low memory is not rescanned every time.
+    pub fn allocate_frame_dma32(&mut self) -> Option<Frame> {
+        self.allocate_run_checked(1, AllocHint::Below(DMA32_FRAMES * PAGE_SIZE), false,
+                                  |allocator| allocator.take_frame_dma32()).ok()
+    }
+
+    fn take_frame_dma32(&mut self) -> Result<Frame, FrameAllocError> {
+        let frame_number = match self.find_free_frame_between(self.dma32_frame.number(), DMA32_FRAMES) {
+            Some(frame_number) => frame_number,
+            None => {
+                self.dma32_frame = Frame::from_number(DMA32_FRAMES);
+                return Err(FrameAllocError::OutOfMemory)
+            },
         };
+        self.set_used(frame_number, true);
+        self.dma32_frame = Frame::from_number(frame_number + 1);
+        Ok(Frame::from_number(frame_number))
+    }
 
-        allocator.map_memory_areas(memory_areas);
-        allocator.map_kernel(kernel_start, kernel_end);
-        allocator.map_multiboot(multiboot_start, multiboot_end);
-        allocator
+    /// Allocates a frame whose number is `color` modulo `num_colors`, e.g. to spread
+    /// data over the sets of a physically indexed cache. Every color keeps its own
+    /// cursor, asking for a different number of colors starts them over.
+    pub fn allocate_frame_with_color(&mut self, color: usize, num_colors: usize) -> Option<Frame> {
+        assert!(num_colors > 0 && num_colors <= MAX_COLORS, "number of colors must be between 1 and {}", MAX_COLORS);
+        assert!(color < num_colors, "color {} does not exist with {} colors", color, num_colors);
+        self.allocate_run_checked(1, AllocHint::Any, false, |allocator| {
+            allocator.take_frame_with_color(color, num_colors).ok_or(FrameAllocError::OutOfMemory)
+        }).ok()
     }
 
-    fn set_used(&mut self, index: usize, value: bool) {
-        if value {
-            self.bitmap[index / BITS_PER_BLOCK] |= 1usize << (index % BITS_PER_BLOCK);
-        } else {
-            self.bitmap[index / BITS_PER_BLOCK] &= !(1usize << (index % BITS_PER_BLOCK));
+    fn take_frame_with_color(&mut self, color: usize, num_colors: usize) -> Option<Frame> {
+        if num_colors != self.color_count {
+            self.color_count = num_colors;
+            self.color_cursors = [self.base_frame; MAX_COLORS];
         }
+        let cursor = self.color_cursors[color];
+        let frame_number = match self.find_free_frame_with_color(cursor, self.last_frame.number(), color, num_colors) {
+            Some(frame_number) => frame_number,
+            None => self.find_free_frame_with_color(self.base_frame, cursor, color, num_colors)?,
+        };
+        self.set_used(frame_number, true);
+        self.color_cursors[color] = frame_number + num_colors;
+        Some(Frame::from_number(frame_number))
     }
 
-    fn find_free_frame_in_block(&mut self, block_number: usize) -> Option<Frame> {
-        if self.block_is_used(block_number) {
-            self.next_frame = BitmapFrameAllocator::first_frame_in_block(block_number + 1);
-            None
-        } else {
-            while self.next_frame <= BitmapFrameAllocator::last_frame_in_block(block_number) {
-                if self.frame_is_used(self.next_frame.number()) {
-                    self.next_frame = Frame{ number: self.next_frame.number() + 1 };
-                } else {
-                    let frame = self.next_frame.clone();
-                    self.set_used(frame.number(), true);
-                    self.next_frame = Frame{ number: frame.number() + 1};
-                    return Some(frame)
+    /// Allocates `count` contiguous frames below 4 GiB and returns the first one
+    pub fn allocate_contiguous_dma32(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(count, AllocHint::Below(DMA32_FRAMES * PAGE_SIZE), false,
+                                  |allocator| allocator.take_run_dma32(count))
+    }
+
+    fn take_run_dma32(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        let cursor = self.dma32_frame.number();
+        let first_frame_number = self.find_free_run_between(cursor, DMA32_FRAMES, count, 1)
+                                     .ok_or(FrameAllocError::OutOfMemory)?;
+        for frame_number in first_frame_number..first_frame_number + count {
+            self.set_used(frame_number, true);
+        }
+        // runs too short for this allocation may be left below the run,
+        // the cursor only skips frames once they are all used
+        if self.find_free_frame_between(cursor, first_frame_number).is_none() {
+            self.dma32_frame = Frame::from_number(first_frame_number + count);
+        }
+        Ok(Frame::from_number(first_frame_number))
+    }
+
+    /// Allocates 2 MiB of contiguous, 2 MiB aligned frames for a huge page and
+    /// returns the first frame. The frames are accounted for individually, so
+    /// they can also be freed one at a time after the huge page is split.
+    pub fn allocate_huge_frame(&mut self) -> Option<Frame> {
+        self.allocate_run_checked(FRAMES_PER_HUGE_FRAME, AllocHint::Any, false, |allocator| {
+            allocator.take_huge_frame().ok_or(FrameAllocError::OutOfMemory)
+        }).ok()
+    }
+
+    fn take_huge_frame(&mut self) -> Option<Frame> {
+        let managed_frames = self.last_frame.number() - self.base_frame;
+        // huge frames are aligned in physical memory, not in the bitmap
+        let mut first_block = (round_up(self.base_frame, FRAMES_PER_HUGE_FRAME) - self.base_frame) / BITS_PER_BLOCK;
+        while (first_block + BLOCKS_PER_HUGE_FRAME) * BITS_PER_BLOCK <= managed_frames {
+            let blocks = &mut self.bitmap[first_block..first_block + BLOCKS_PER_HUGE_FRAME];
+            if blocks.iter().all(|&block| block == 0) {
+                for block in blocks.iter_mut() {
+                    *block = core::usize::MAX;
                 }
+                self.free_frames -= FRAMES_PER_HUGE_FRAME;
+                return Some(self.first_frame_in_block(first_block))
             }
-            None
+            first_block += BLOCKS_PER_HUGE_FRAME;
         }
+        None
     }
 
-    pub fn first_frame_in_block(block_number: usize) -> Frame {
-        Frame{ number: block_number * BITS_PER_BLOCK }
+    /// Frees a huge frame returned by `allocate_huge_frame`
+    pub fn deallocate_huge_frame(&mut self, frame: Frame) {
+        assert!(frame.number() % FRAMES_PER_HUGE_FRAME == 0, "{} is not a huge frame", frame);
+        let end = Frame::from_number(frame.number() + FRAMES_PER_HUGE_FRAME - 1);
+        self.deallocate_range(Frame::range_inclusive(frame, end));
     }
 
-    pub fn last_frame_in_block(block_number: usize) -> Frame {
-        Frame{ number: block_number * BITS_PER_BLOCK + BITS_PER_BLOCK - 1 }
+    /// Claims a specific frame, e.g. the AP startup trampoline at 0x8000
+    pub fn allocate_frame_at(&mut self, frame: Frame) -> Result<Frame, FrameAllocError> {
+        self.allocate_run_checked(1, AllocHint::Any, false, |allocator| allocator.take_frame_at(frame))
     }
 
-    pub fn get_block_number(frame_number: usize) -> usize {
-        frame_number / BITS_PER_BLOCK
+    fn take_frame_at(&mut self, frame: Frame) -> Result<Frame, FrameAllocError> {
+        if !self.frame_in_range(frame.number()) {
+            Err(FrameAllocError::InvalidRange)
+        } else if self.frame_is_used(frame.number()) {
+            Err(FrameAllocError::AlreadyAllocated)
+        } else {
+            self.set_used(frame.number(), true);
+            Ok(frame)
+        }
     }
 
-    pub fn block_is_used(&self, index: usize) -> bool {
-        self.bitmap[index] == core::usize::MAX
+    /// Marks the frame used and returns whether it already was, e.g. to claim a
+    /// framebuffer the bootloader reported. Frames outside of managed memory are
+    /// never handed out, so they count as used and are left alone.
+    pub fn claim_frame(&mut self, frame: Frame) -> bool {
+        let frame_number = frame.number();
+        if !self.frame_in_range(frame_number) {
+            return true
+        }
+        let was_used = self.frame_is_used(frame_number);
+        self.set_used(frame_number, true);
+        was_used
     }
 
-    pub fn frame_is_used(&self, index: usize) -> bool {
-        (self.bitmap[index / BITS_PER_BLOCK] & (1usize << (index % BITS_PER_BLOCK))) != 0
+    /// Frees a frame, refusing to free one that is not allocated or reserved
+    pub fn try_deallocate_frame(&mut self, frame: Frame) -> Result<(), FrameAllocError> {
+        if self.drop_reference(frame)? {
+            self.free_frame(frame);
+        }
+        Ok(())
+    }
+
+    /// Drops a reference to an allocated frame and returns whether it was the
+    /// last one, so the frame has to be freed. Frames are not freed here.
+    fn drop_reference(&mut self, frame: Frame) -> Result<bool, FrameAllocError> {
+        self.check_deallocation(frame)?;
+        let bit = self.bit_index(frame.number());
+        if let Some(ref mut refcounts) = self.refcounts {
+            if refcounts[bit] > 1 {
+                refcounts[bit] -= 1;
+                return Ok(false)
+            }
+            refcounts[bit] = 0;
+        }
+        Ok(true)
+    }
+
+    fn check_deallocation(&self, frame: Frame) -> Result<(), FrameAllocError> {
+        if !self.frame_in_range(frame.number()) {
+            Err(FrameAllocError::InvalidRange)
+        } else if self.frame_is_reserved(frame.number()) {
+            Err(FrameAllocError::Reserved)
+        } else if !self.frame_is_used(frame.number()) {
+            Err(FrameAllocError::DoubleFree)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn free_frame(&mut self, frame: Frame) {
+        self.set_used(frame.number(), false);
+        if let Some(trace) = self.trace {
+            trace(FrameEvent::Deallocated(frame));
+        }
+    }
+
+    /// Starts counting references to frames, one byte per managed frame, e.g. to
+    /// share frames copy-on-write. Allocated frames start with one reference and
+    /// `deallocate_frame` only frees a frame once its last reference is dropped.
+    /// `deallocate_frame_zeroed` only zeroes a frame once it is freed as well,
+    /// `deallocate_range` ignores the counts.
+    pub fn track_refcounts(&mut self, refcounts: &'a mut [u8]) {
+        let managed_frames = self.last_frame.number() - self.base_frame;
+        assert!(refcounts.len() >= managed_frames, "Reference count table is too small");
+        for frame_number in self.base_frame..self.last_frame.number() {
+            let bit = self.bit_index(frame_number);
+            refcounts[bit] = if self.frame_is_used(frame_number) { 1 } else { 0 };
+        }
+        self.refcounts = Some(refcounts);
+    }
+
+    /// Adds a reference to an allocated frame, e.g. when mapping it a second time
+    pub fn increment_ref(&mut self, frame: Frame) {
+        assert!(self.frame_in_range(frame.number()) && self.frame_is_used(frame.number()),
+                "{} is not allocated", frame);
+        let bit = self.bit_index(frame.number());
+        let refcounts = self.refcounts.as_mut().expect("reference counts are not tracked");
+        // frames claimed behind the allocator's back have one reference as well
+        let count = core::cmp::max(refcounts[bit], 1);
+        assert!(count < core::u8::MAX, "reference count of {} overflows", frame);
+        refcounts[bit] = count + 1;
+    }
+
+    /// Returns an iterator which allocates up to `count` frames, one per
+    /// call to `next`. `count` may not exceed `ALLOCATE_ITER_CAPACITY`.
+    pub fn allocate_iter<'b>(&'b mut self, count: usize) -> AllocateIter<'b, 'a> {
+        assert!(count <= ALLOCATE_ITER_CAPACITY, "cannot allocate {} frames through an iterator", count);
+        AllocateIter {
+            allocator: self,
+            remaining: count,
+            exhausted: false,
+            yielded: [0; ALLOCATE_ITER_CAPACITY],
+            yielded_count: 0,
+        }
+    }
+
+    /// Fills `out` with free, not necessarily contiguous, frames in a single pass
+    /// over the bitmap, starting at the same cursor as `allocate_frame`.
+    /// Either the whole slice is filled or, if there are not enough free frames,
+    /// every frame taken so far is freed again and `OutOfMemory` is returned.
+    pub fn allocate_frames_into(&mut self, out: &mut [Frame]) -> Result<usize, FrameAllocError> {
+        let filled = self.allocate_checked(out.len(), AllocHint::Any, false,
+                                           |allocator| allocator.take_frames_into(out))?;
+        for &frame in out.iter() {
+            self.hand_out(frame);
+        }
+        self.check_low_watermark();
+        Ok(filled)
     }
 
-    fn map_memory_areas(&mut self, memory_areas: MemoryAreaIter) {
-        let last_area = memory_areas.clone().max_by_key(|area| area.base_addr).unwrap();
-        self.last_frame = Frame::containing_address(last_area.base_addr as usize + last_area.length as usize);
+    fn take_frames_into(&mut self, out: &mut [Frame]) -> Result<usize, FrameAllocError> {
+        let cursor = core::cmp::min(self.next_frame.number(), self.last_frame.number());
         let last_frame_number = self.last_frame.number();
-        assert!(last_frame_number <= NUM_OF_FRAMES, "Bitmap used by frame allocator is too small");
-        self.set_used(last_frame_number, true);
+        let mut filled = self.take_free_frames(cursor, last_frame_number, out, 0);
+        let base_frame = self.base_frame;
+        filled = self.take_free_frames(base_frame, cursor, out, filled);
 
+        if filled < out.len() {
+            for frame in &out[..filled] {
+                self.set_used(frame.number(), false);
+            }
+            return Err(FrameAllocError::OutOfMemory)
+        }
+        if let Some(frame) = out.last() {
+            self.next_frame = Frame::from_number(frame.number() + 1);
+        }
+        Ok(filled)
+    }
 
-        for (area1, area2) in memory_areas.clone().zip(memory_areas.clone().skip(1)) {
-            let start_occupied = Frame::containing_address((area1.base_addr + area1.length) as usize);
-            let end_occupied = Frame::containing_address((area2.base_addr - 1) as usize);
+    /// Marks free frames in `[start, end)` as used, one block at a time, and stores
+    /// them in `out` from index `filled` on. Returns the new number of filled entries.
+    fn take_free_frames(&mut self, start: usize, end: usize, out: &mut [Frame], mut filled: usize) -> usize {
+        let mut frame_number = start;
+        while frame_number < end && filled < out.len() {
+            let block_number = self.get_block_number(frame_number);
+            let offset = frame_number % BITS_PER_BLOCK;
+            let bits = core::cmp::min(BITS_PER_BLOCK - offset, end - frame_number);
+            let first_frame_number = self.first_frame_in_block(block_number).number();
 
-            for frame in Frame::range_inclusive(start_occupied, end_occupied) {
-                self.set_used(frame.number(), true);
+            let mut free_bits = !self.bitmap[block_number] & block_mask(offset, bits);
+            let mut taken_bits = 0;
+            while free_bits != 0 && filled < out.len() {
+                let bit = free_bits & free_bits.wrapping_neg();
+                free_bits &= !bit;
+                taken_bits |= bit;
+                out[filled] = Frame::from_number(first_frame_number + bit.trailing_zeros() as usize);
+                filled += 1;
             }
+            self.bitmap[block_number] |= taken_bits;
+            self.free_frames -= taken_bits.count_ones() as usize;
+            frame_number += bits;
         }
+        filled
     }
 
-    fn map_kernel(&mut self, kernel_start: usize, kernel_end: usize) {
-        for frame in Frame::range_inclusive(Frame::containing_address(kernel_start), 
-                                            Frame::containing_address(kernel_end)) {
-            self.set_used(frame.number(), true);
+    /// Frees the frames of a range returned by `allocate_frames`. If any frame
+    /// in the range is not allocated or is reserved, nothing is freed and that
+    /// frame is returned.
+    pub fn deallocate_frames(&mut self, range: FrameRangeInclusive) -> Result<(), Frame> {
+        let frames = Frame::range_inclusive(range.start, range.end);
+        for frame in frames {
+            if !self.frame_in_range(frame.number()) || !self.frame_is_used(frame.number())
+               || self.frame_is_reserved(frame.number()) {
+                return Err(frame)
+            }
         }
+        self.deallocate_range(range);
+        Ok(())
     }
 
-    fn map_multiboot(&mut self, multiboot_start: usize, multiboot_end: usize) {
-        for frame in Frame::range_inclusive(Frame::containing_address(multiboot_start), 
-                                            Frame::containing_address(multiboot_end)) {
-            self.set_used(frame.number(), true);
+    /// Frees every frame in `frames` once, e.g. the frames of a process which
+    /// shares some pages. All frames are checked before any is freed, so a frame
+    /// which is already free before the call is still a double free. With
+    /// reference counts every repeat of a shared frame drops one reference.
+    pub fn deallocate_frames_dedup(&mut self, frames: &[Frame]) {
+        for &frame in frames {
+            let result = self.check_deallocation(frame);
+            assert!(result.is_ok(), "frame deallocation failed: {:?}", result);
+        }
+        for &frame in frames {
+            if self.frame_is_used(frame.number()) {
+                self.deallocate_frame(frame);
+            }
+        }
+    }
+
+    /// Frees every frame in the range, clearing whole bitmap blocks at once
+    pub fn deallocate_range(&mut self, range: FrameRangeInclusive) {
+        if range.start > range.end {
+            return;
+        }
+        assert!(self.frame_in_range(range.end.number()), "{} exceeds managed memory", range.end);
+
+        let end = range.end.number() + 1;
+        let mut frame_number = range.start.number();
+        while frame_number < end {
+            let block_number = self.get_block_number(frame_number);
+            let offset = frame_number % BITS_PER_BLOCK;
+            let bits = core::cmp::min(BITS_PER_BLOCK - offset, end - frame_number);
+            let mut mask = block_mask(offset, bits);
+            debug_assert!(self.bitmap[block_number] & mask == mask,
+                          "range contains frames which are not allocated");
+            debug_assert!(self.reserved[block_number] & mask == 0, "range contains reserved frames");
+            mask &= !self.reserved[block_number];
+            self.free_frames += (self.bitmap[block_number] & mask).count_ones() as usize;
+            if let Some(trace) = self.trace {
+                let freed = self.bitmap[block_number] & mask;
+                for bit in (0..BITS_PER_BLOCK).filter(|&bit| freed & (1 << bit) != 0) {
+                    trace(FrameEvent::Deallocated(Frame::from_number(frame_number - offset + bit)));
+                }
+            }
+            self.bitmap[block_number] &= !mask;
+            if let Some(ref mut zeroed) = self.zeroed {
+                zeroed[block_number] &= !mask;
+            }
+            frame_number += bits;
+        }
+        self.update_cursors_on_free(range.start.number(), range.end.number());
+    }
+
+    /// Number of used frames in managed memory
+    pub fn used_frame_count(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Number of free frames in managed memory
+    pub fn free_frame_count(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Iterates over every free frame in managed memory, lowest first
+    pub fn free_frames(&self) -> FreeFrameIter {
+        FreeFrameIter {
+            bitmap: self.bitmap,
+            base_frame: self.base_frame,
+            next_frame: self.base_frame,
+            last_frame: self.last_frame.number(),
+        }
+    }
+
+    /// Size of managed memory in bytes, including frames which are reserved
+    pub fn total_memory_bytes(&self) -> usize {
+        self.total_frames * PAGE_SIZE
+    }
+
+    /// Start and end address of managed memory, `end` being exclusive,
+    /// e.g. to size the direct map of physical memory
+    pub fn managed_range(&self) -> (usize, usize) {
+        (Frame::from_number(self.base_frame).start_address(), self.last_frame.start_address())
+    }
+
+    /// Number of words `snapshot` writes, enough to cover managed memory
+    pub fn snapshot_len(&self) -> usize {
+        self.get_block_number(self.last_frame.number()) + 1
+    }
+
+    /// Copies the bitmap of managed memory to `out`, e.g. to save it for hibernation.
+    /// Returns the number of words written.
+    pub fn snapshot(&self, out: &mut [usize]) -> Result<usize, FrameAllocError> {
+        let len = self.snapshot_len();
+        if out.len() < len {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        out[..len].copy_from_slice(&self.bitmap[..len]);
+        Ok(len)
+    }
+
+    /// Replaces the bitmap with one written by `snapshot` of an allocator managing
+    /// the same memory. Fails without changing anything if the length does
+    /// not match or a reserved frame would become free.
+    pub fn restore(&mut self, data: &[usize]) -> Result<(), FrameAllocError> {
+        let len = self.snapshot_len();
+        if data.len() != len {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        if data.iter().zip(self.reserved.iter()).any(|(&block, &reserved)| block & reserved != reserved) {
+            return Err(FrameAllocError::Reserved);
+        }
+        self.bitmap[..len].copy_from_slice(data);
+
+        let last_frame_number = self.last_frame.number();
+        self.free_frames = self.count_free_frames();
+        // what was zeroed is unknown, and the cursors may point at frames which are used now
+        if let Some(ref mut zeroed) = self.zeroed {
+            for block in zeroed.iter_mut() {
+                *block = 0;
+            }
+        }
+        self.second_scan = false;
+        self.next_frame = Frame::from_number(self.base_frame);
+        self.low_frame = Frame::from_number(self.base_frame);
+        self.high_frame = Frame::from_number(last_frame_number);
+        self.dma32_frame = Frame::from_number(self.base_frame);
+        self.lowest_free_hint = core::usize::MAX;
+        Ok(())
+    }
+
+    /// Free frames according to the bitmap, counted from scratch
+    fn count_free_frames(&self) -> usize {
+        let last_frame_number = self.last_frame.number();
+        let mut free_frames = 0;
+        for block_number in 0..self.snapshot_len() {
+            let first_frame_number = self.first_frame_in_block(block_number).number();
+            let bits = core::cmp::min(BITS_PER_BLOCK, last_frame_number - first_frame_number);
+            if bits > 0 {
+                free_frames += (!self.bitmap[block_number] & block_mask(0, bits)).count_ones() as usize;
+            }
         }
+        free_frames
     }
-}
\ No newline at end of file
+
+    /// Writes the state to `out` for post-mortem debugging, e.g. over serial.
+    /// The header holds `SERIALIZE_MAGIC`, the format version and word size, the
+    /// base frame, `last_frame` and the free frame count, all little endian. After
+    /// it come the used and the reserved bitmap of managed memory, each as runs of
+    /// equal words, since most of memory is either all free or all used.
+    /// Returns the number of bytes written.
+    pub fn serialize_into(&self, out: &mut [u8]) -> Result<usize, SerializeError> {
+        let mut writer = ByteWriter { bytes: out, len: 0 };
+        for &byte in SERIALIZE_MAGIC.iter() {
+            writer.put(byte as usize, 1)?;
+        }
+        writer.put(SERIALIZE_VERSION as usize, 1)?;
+        writer.put(WORD_BYTES, 1)?;
+        writer.put(self.base_frame, WORD_BYTES)?;
+        writer.put(self.last_frame.number(), WORD_BYTES)?;
+        writer.put(self.free_frames, WORD_BYTES)?;
+        let len = self.snapshot_len();
+        writer.put_runs(&self.bitmap[..len])?;
+        writer.put_runs(&self.reserved[..len])?;
+        Ok(writer.len)
+    }
+
+    /// Rebuilds an allocator written by `serialize_into` in `bitmap` and `reserved`,
+    /// e.g. to inspect a dump in tests. Cursors, hooks and settings start out like
+    /// after `new`. Bytes after the serialized state are ignored.
+    pub fn deserialize_from(bitmap: &'a mut [usize], reserved: &'a mut [usize],
+                            bytes: &[u8]) -> Result<BitmapFrameAllocator<'a>, SerializeError>
+    {
+        let mut reader = ByteReader { bytes: bytes, pos: 0 };
+        for &byte in SERIALIZE_MAGIC.iter() {
+            if reader.get(1)? != byte as usize {
+                return Err(SerializeError::BadMagic);
+            }
+        }
+        let version = reader.get(1)? as u8;
+        if version != SERIALIZE_VERSION {
+            return Err(SerializeError::UnsupportedVersion(version));
+        }
+        let word_bytes = reader.get(1)? as u8;
+        if word_bytes as usize != WORD_BYTES {
+            return Err(SerializeError::WordSizeMismatch(word_bytes));
+        }
+        let base_frame = reader.get(WORD_BYTES)?;
+        let last_frame_number = reader.get(WORD_BYTES)?;
+        let free_frames = reader.get(WORD_BYTES)?;
+        if base_frame % BITS_PER_BLOCK != 0 || last_frame_number < base_frame {
+            return Err(SerializeError::Corrupt);
+        }
+        let len = (last_frame_number - base_frame) / BITS_PER_BLOCK + 1;
+        if bitmap.len() < len || reserved.len() < len {
+            return Err(SerializeError::BufferTooSmall);
+        }
+
+        for block in bitmap.iter_mut() {
+            *block = core::usize::MAX;
+        }
+        let mut allocator = BitmapFrameAllocator::empty(bitmap, reserved, Frame::from_number(base_frame));
+        reader.get_runs(&mut allocator.bitmap[..len])?;
+        reader.get_runs(&mut allocator.reserved[..len])?;
+        allocator.last_frame = Frame::from_number(last_frame_number);
+        allocator.mark_unmanaged_tail_used();
+        allocator.high_frame = Frame::from_number(last_frame_number);
+        allocator.total_frames = last_frame_number - base_frame;
+        allocator.free_frames = allocator.count_free_frames();
+        if allocator.free_frames != free_frames {
+            return Err(SerializeError::Corrupt);
+        }
+        Ok(allocator)
+    }
+
+    /// Is the frame part of the memory managed by this allocator?
+    fn frame_in_range(&self, frame_number: usize) -> bool {
+        self.base_frame <= frame_number && frame_number < self.last_frame.number()
+    }
+
+    /// Keeps `frames` free frames back from `allocate_frame` for
+    /// `allocate_frame_critical`, e.g. for page tables needed to handle a page fault
+    pub fn set_reserve(&mut self, frames: usize) {
+        self.reserve = frames;
+    }
+
+    /// Details of the last failed allocation, cleared by the next successful one.
+    /// Covers every method which allocates frames.
+    pub fn last_failure(&self) -> Option<&LastFailure> {
+        self.last_failure.as_ref()
+    }
+
+    fn record_result<T>(&mut self, result: Result<T, FrameAllocError>, requested: usize) -> Result<T, FrameAllocError> {
+        self.last_failure = match result {
+            Ok(_) => None,
+            Err(kind) => Some(LastFailure {
+                kind: kind,
+                requested: requested,
+                free_at_time: self.free_frames,
+                cursor: self.next_frame.number(),
+            }),
+        };
+        result
+    }
+
+    /// Installs a function which is called before an allocation fails for lack
+    /// of memory. It may free frames and return `Retry`, but must not allocate.
+    pub fn set_oom_handler(&mut self, handler: fn(&mut BitmapFrameAllocator<'a>, &OomInfo) -> OomAction) {
+        self.oom_handler = Some(handler);
+    }
+
+    /// Installs a function which is called with every frame allocated
+    /// or freed, e.g. to track down leaks
+    pub fn set_trace(&mut self, trace: fn(FrameEvent)) {
+        self.trace = Some(trace);
+    }
+
+    /// Installs a function which is called when `allocate_frame` leaves
+    /// `free_frames_threshold` or fewer free frames. It is called again only
+    /// after the free frame count has risen above the threshold.
+    pub fn set_low_watermark(&mut self, free_frames_threshold: usize, callback: fn()) {
+        self.low_watermark = Some((free_frames_threshold, callback));
+        self.low_watermark_armed = true;
+    }
+
+    /// Runs the out of memory handler, returns true if the allocation should be retried
+    fn out_of_memory(&mut self, requested: usize, hint: AllocHint) -> bool {
+        match self.oom_handler {
+            Some(handler) => {
+                let info = OomInfo {
+                    requested: requested,
+                    free_frames: self.free_frames,
+                    hint: hint,
+                };
+                handler(self, &info) == OomAction::Retry
+            },
+            None => false,
+        }
+    }
+
+    /// Common part of every allocation method. `search` takes `count` frames from
+    /// the bitmap, but unless the allocation is `critical` not the frames kept
+    /// back by `set_reserve`. If memory runs out, the out of memory handler may
+    /// free some and `search` runs once more. The result ends up in `last_failure`.
+    fn allocate_checked<T, S>(&mut self, count: usize, hint: AllocHint, critical: bool, mut search: S)
+                              -> Result<T, FrameAllocError>
+        where S: FnMut(&mut BitmapFrameAllocator<'a>) -> Result<T, FrameAllocError>
+    {
+        if let Some((threshold, _)) = self.low_watermark {
+            if self.free_frames > threshold {
+                self.low_watermark_armed = true;
+            }
+        }
+        let mut result = self.search_unreserved(count, critical, &mut search);
+        if result.as_ref().err() == Some(&FrameAllocError::OutOfMemory) && self.out_of_memory(count, hint) {
+            result = self.search_unreserved(count, critical, &mut search);
+        }
+        self.record_result(result, count)
+    }
+
+    fn search_unreserved<T, S>(&mut self, count: usize, critical: bool, search: &mut S) -> Result<T, FrameAllocError>
+        where S: FnMut(&mut BitmapFrameAllocator<'a>) -> Result<T, FrameAllocError>
+    {
+        if !critical && self.reserve > 0 && self.free_frames.saturating_sub(self.reserve) < count {
+            return Err(FrameAllocError::OutOfMemory)
+        }
+        search(self)
+    }
+
+    /// `allocate_checked` for a search which takes `count` contiguous frames
+    /// and returns the first one, which are then handed out
+    fn allocate_run_checked<S>(&mut self, count: usize, hint: AllocHint, critical: bool, search: S)
+                               -> Result<Frame, FrameAllocError>
+        where S: FnMut(&mut BitmapFrameAllocator<'a>) -> Result<Frame, FrameAllocError>
+    {
+        let first = self.allocate_checked(count, hint, critical, search)?;
+        for frame_number in first.number()..first.number() + count {
+            self.hand_out(Frame::from_number(frame_number));
+        }
+        self.check_low_watermark();
+        Ok(first)
+    }
+
+    /// Prepares a frame just taken from the bitmap for its caller
+    fn hand_out(&mut self, frame: Frame) {
+        let bit = self.bit_index(frame.number());
+        if let Some(ref mut refcounts) = self.refcounts {
+            refcounts[bit] = 1;
+        }
+        if self.zero_on_alloc {
+            self.zero_allocated_frame(&frame);
+        }
+        if let Some(trace) = self.trace {
+            trace(FrameEvent::Allocated(frame));
+        }
+    }
+
+    fn check_low_watermark(&mut self) {
+        if let Some((threshold, callback)) = self.low_watermark {
+            if self.low_watermark_armed && self.free_frames <= threshold {
+                self.low_watermark_armed = false;
+                callback();
+            }
+        }
+    }
+
+    fn take_run_by_policy(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        match self.policy {
+            AllocPolicy::BestFit => self.take_best_fit_run(count),
+            _ => self.take_free_run(count, 1),
+        }
+    }
+
+    /// Defaults to `NextFit`
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Makes next-fit allocations cycle through all of memory, so a freed frame is
+    /// only handed out again once the cursor wraps around to it, e.g. to spread
+    /// wear or to make use-after-free bugs less likely to hit reused memory.
+    /// Defaults to `false`, where `allocate_frame` goes back to the lowest frame
+    /// freed below the cursor.
+    pub fn set_rotate(&mut self, rotate: bool) {
+        self.rotate = rotate;
+        self.lowest_free_hint = core::usize::MAX;
+    }
+
+    /// Defaults to `Up`. Contiguous allocations always search upwards.
+    pub fn set_scan_direction(&mut self, direction: ScanDirection) {
+        self.direction = direction;
+        self.second_scan = false;
+        self.lowest_free_hint = core::usize::MAX;
+        self.next_frame = match direction {
+            ScanDirection::Up => Frame::from_number(self.base_frame),
+            ScanDirection::Down => self.last_frame,
+        };
+    }
+
+    /// Finds and allocates a single frame according to the allocation policy
+    fn allocate_frame_by_policy(&mut self) -> Option<Frame> {
+        match (self.direction, self.policy) {
+            (ScanDirection::Up, AllocPolicy::NextFit) => self.next_free_frame(),
+            (ScanDirection::Up, _) => self.find_frame_with_hint(AllocHint::PreferLow),
+            (ScanDirection::Down, AllocPolicy::NextFit) => self.prev_free_frame(),
+            (ScanDirection::Down, _) => self.find_frame_with_hint(AllocHint::PreferHigh),
+        }
+    }
+
+    /// Allocates the lowest free frame other than frame 0, the type says so.
+    /// Even after `release_null_frame` frame 0 is skipped here, so `None` means
+    /// that no other frame is free.
+    pub fn allocate_nonzero_frame(&mut self) -> Option<NonZeroFrame> {
+        let start = Frame::from_number(core::cmp::max(self.base_frame, 1));
+        let end = self.last_frame;
+        let frame = self.allocate_run_checked(1, AllocHint::Any, false,
+                                              |allocator| allocator.take_frame_between(start, end)).ok()?;
+        Some(NonZeroFrame::new(frame).expect("the search starts at frame 1"))
+    }
+
+    /// Like `allocate_frame`, but may use the frames kept back by `set_reserve`
+    pub fn allocate_frame_critical(&mut self) -> Option<Frame> {
+        self.allocate_run_checked(1, AllocHint::Any, true, |allocator| {
+            allocator.allocate_frame_by_policy().ok_or(FrameAllocError::OutOfMemory)
+        }).ok()
+    }
+
+    /// Sets the function used to zero frames. It must be able to write
+    /// to any frame managed by this allocator.
+    pub unsafe fn set_frame_zeroer(&mut self, zeroer: fn(&Frame)) {
+        self.zeroer = zeroer;
+    }
+
+    /// Starts tracking which free frames are known to contain only zeros,
+    /// so that frames zeroed when freed are not zeroed again when allocated
+    pub fn track_zeroed_frames(&mut self, zeroed: &'a mut [usize]) {
+        assert!(zeroed.len() >= self.bitmap.len(), "Bitmap of zeroed frames is too small");
+        for block in zeroed.iter_mut() {
+            *block = 0;
+        }
+        self.zeroed = Some(zeroed);
+    }
+
+    /// Makes `allocate_frame` zero every frame it returns. Frames are zeroed
+    /// by the frame zeroer, which by default writes through the identity
+    /// mapping of physical memory, so only enable this while that mapping
+    /// exists or after installing a zeroer with `set_frame_zeroer`.
+    pub fn set_zero_on_alloc(&mut self, zero_on_alloc: bool) {
+        self.zero_on_alloc = zero_on_alloc;
+    }
+
+    /// Allocates a frame like `allocate_frame` and zeroes it, whether or not
+    /// `set_zero_on_alloc` is set. The frame zeroer has to be able to write to
+    /// the frame, with the default one physical memory must be identity mapped.
+    pub fn allocate_frame_zeroed(&mut self) -> Option<Frame> {
+        let frame = self.allocate_frame()?;
+        if !self.zero_on_alloc {
+            self.zero_allocated_frame(&frame);
+        }
+        Some(frame)
+    }
+
+    /// Zeroes a frame which was just allocated, unless it is known to be zeroed already
+    fn zero_allocated_frame(&mut self, frame: &Frame) {
+        let bit = self.bit_index(frame.number());
+        let known_zero = match self.zeroed {
+            Some(ref mut zeroed) => {
+                let known_zero = bit_is_set(zeroed, bit);
+                set_bit(zeroed, bit, false);
+                known_zero
+            },
+            None => false,
+        };
+        if !known_zero {
+            (self.zeroer)(frame);
+        }
+    }
+
+    /// Zeroes a frame and frees it, remembering it is zeroed if zeroed frames are tracked.
+    /// A shared frame only loses a reference and is left untouched.
+    pub fn deallocate_frame_zeroed(&mut self, frame: Frame) {
+        let result = self.drop_reference(frame);
+        assert!(result.is_ok(), "frame deallocation failed: {:?}", result);
+        if result != Ok(true) {
+            return
+        }
+        (self.zeroer)(&frame);
+        self.free_frame(frame);
+        let bit = self.bit_index(frame.number());
+        if let Some(ref mut zeroed) = self.zeroed {
+            set_bit(zeroed, bit, true);
+        }
+    }
+
+    fn set_used(&mut self, index: usize, value: bool) {
+        // only count real transitions, initialization marks some frames used twice
+        if self.frame_in_range(index) && self.frame_is_used(index) != value {
+            if value {
+                self.free_frames -= 1;
+            } else {
+                self.free_frames += 1;
+            }
+        }
+        let bit = self.bit_index(index);
+        set_bit(self.bitmap, bit, value);
+        if !value {
+            if let Some(ref mut zeroed) = self.zeroed {
+                set_bit(zeroed, bit, false);
+            }
+            self.update_cursors_on_free(index, index);
+        }
+    }
+
+    /// Keeps the hint cursors valid after the frames `first..=last` were freed
+    fn update_cursors_on_free(&mut self, first: usize, last: usize) {
+        self.lowest_free_hint = core::cmp::min(self.lowest_free_hint, first);
+        if first < self.low_frame.number() {
+            self.low_frame = Frame::from_number(first);
+        }
+        if first < self.dma32_frame.number() {
+            self.dma32_frame = Frame::from_number(first);
+        }
+        if last >= self.high_frame.number() && self.frame_in_range(last) {
+            self.high_frame = Frame::from_number(last + 1);
+        }
+    }
+
+    /// Allocates a frame according to `hint`. `PreferLow` and `PreferHigh`
+    /// keep their own cursors, so neither disturbs the other or `allocate_frame`.
+    pub fn allocate_frame_with_hint(&mut self, hint: AllocHint) -> Option<Frame> {
+        if hint == AllocHint::Any {
+            return self.allocate_frame()
+        }
+        self.allocate_run_checked(1, hint, false, |allocator| {
+            allocator.find_frame_with_hint(hint).ok_or(FrameAllocError::OutOfMemory)
+        }).ok()
+    }
+
+    fn find_frame_with_hint(&mut self, hint: AllocHint) -> Option<Frame> {
+        match hint {
+            AllocHint::Any => self.allocate_frame_by_policy(),
+            AllocHint::PreferLow => {
+                let last_frame_number = self.last_frame.number();
+                let frame_number = self.find_free_frame_between(self.low_frame.number(), last_frame_number)?;
+                self.set_used(frame_number, true);
+                self.low_frame = Frame::from_number(frame_number + 1);
+                Some(Frame::from_number(frame_number))
+            },
+            AllocHint::PreferHigh => {
+                let frame_number = self.find_last_free_frame_before(self.high_frame.number())?;
+                self.set_used(frame_number, true);
+                self.high_frame = Frame::from_number(frame_number);
+                Some(Frame::from_number(frame_number))
+            },
+            AllocHint::Below(limit) => self.take_frame_below(limit).ok(),
+        }
+    }
+
+    /// Next-fit search for a free frame, starting at the cursor
+    fn next_free_frame(&mut self) -> Option<Frame> {
+        if self.free_frames == 0 {
+            self.next_frame = Frame::from_number(self.base_frame);
+            return None
+        }
+
+        // resume at a frame freed below the cursor instead of wrapping around to it
+        if !self.rotate && self.lowest_free_hint < self.next_frame.number() {
+            self.next_frame = Frame::from_number(self.lowest_free_hint);
+        }
+        self.lowest_free_hint = core::usize::MAX;
+
+        // the second scan only has to cover what the first one skipped
+        let first_scan_start = self.next_frame.number();
+        self.second_scan = false;
+        loop {
+            let scan_end = if self.second_scan {
+                core::cmp::min(first_scan_start, self.last_frame.number())
+            } else {
+                self.last_frame.number()
+            };
+            match self.next_frame.number() >= scan_end {
+                false => {
+                    let block_number = self.get_block_number(self.next_frame.number());
+                    let frame = self.find_free_frame_in_block(block_number);
+                    if frame.is_some() {
+                        return frame
+                    }
+                },
+                true if !self.second_scan => {
+                    self.second_scan = true;
+                    self.next_frame = Frame::from_number(self.base_frame);
+                },
+                true => {
+                    self.second_scan = false;
+                    self.next_frame = Frame::from_number(self.base_frame);
+                    return None
+                }
+            }
+        }
+    }
+
+    /// Mirror of `next_free_frame`, searching downwards from the cursor
+    fn prev_free_frame(&mut self) -> Option<Frame> {
+        if self.free_frames == 0 {
+            self.next_frame = self.last_frame;
+            return None
+        }
+
+        // the second scan only has to cover what the first one skipped
+        let first_scan_start = self.next_frame.number();
+        self.second_scan = false;
+        loop {
+            let scan_end = if self.second_scan { first_scan_start } else { self.base_frame };
+            match self.next_frame.number() <= scan_end {
+                false => {
+                    let block_number = self.get_block_number(self.next_frame.number() - 1);
+                    let frame = self.find_free_frame_in_block_down(block_number);
+                    if frame.is_some() {
+                        return frame
+                    }
+                },
+                true if !self.second_scan => {
+                    self.second_scan = true;
+                    self.next_frame = self.last_frame;
+                },
+                true => {
+                    self.second_scan = false;
+                    self.next_frame = self.last_frame;
+                    return None
+                }
+            }
+        }
+    }
+
+    /// Allocates the highest free frame of the block below the cursor
+    fn find_free_frame_in_block_down(&mut self, block_number: usize) -> Option<Frame> {
+        #[cfg(test)]
+        self.count_scanned_block();
+        // free frames below the cursor
+        let bits = (self.next_frame.number() - 1) % BITS_PER_BLOCK + 1;
+        let free_bits = !self.bitmap[block_number] & block_mask(0, bits);
+        if free_bits == 0 {
+            self.next_frame = self.first_frame_in_block(block_number);
+            None
+        } else {
+            let frame_number = self.first_frame_in_block(block_number).number()
+                               + (BITS_PER_BLOCK - 1 - free_bits.leading_zeros() as usize);
+            self.set_used(frame_number, true);
+            self.next_frame = Frame::from_number(frame_number);
+            Some(Frame::from_number(frame_number))
+        }
+    }
+
+    fn find_free_frame_in_block(&mut self, block_number: usize) -> Option<Frame> {
+        #[cfg(test)]
+        self.count_scanned_block();
+        // free frames at or after the cursor
+        let offset = self.next_frame.number() % BITS_PER_BLOCK;
+        let free_bits = !self.bitmap[block_number] & (core::usize::MAX << offset);
+        if free_bits == 0 {
+            // never step past managed memory, so the scan ends or wraps instead of
+            // indexing beyond the last block
+            let next_block_start = self.first_frame_in_block(block_number + 1).number();
+            self.next_frame = Frame::from_number(core::cmp::min(next_block_start, self.last_frame.number()));
+            None
+        } else {
+            let frame_number = self.first_frame_in_block(block_number).number()
+                               + free_bits.trailing_zeros() as usize;
+            self.set_used(frame_number, true);
+            self.next_frame = Frame::from_number(frame_number + 1);
+            Some(Frame::from_number(frame_number))
+        }
+    }
+
+    /// Returns the number of the first free frame in `[start, end)`, clamped to managed memory
+    fn find_free_frame_between(&self, start: usize, end: usize) -> Option<usize> {
+        let end = core::cmp::min(end, self.last_frame.number());
+        let mut frame_number = core::cmp::max(start, self.base_frame);
+        while frame_number < end {
+            #[cfg(test)]
+            self.count_scanned_block();
+            let block_number = self.get_block_number(frame_number);
+            let free_bits = !self.bitmap[block_number] & (core::usize::MAX << (frame_number % BITS_PER_BLOCK));
+            if free_bits == 0 {
+                frame_number = self.first_frame_in_block(block_number + 1).number();
+            } else {
+                frame_number = self.first_frame_in_block(block_number).number()
+                               + free_bits.trailing_zeros() as usize;
+                return if frame_number < end { Some(frame_number) } else { None }
+            }
+        }
+        None
+    }
+
+    /// Returns the number of the first free frame of the color in `start..end`, only
+    /// looking at frames of that color and skipping blocks which are used completely
+    fn find_free_frame_with_color(&self, start: usize, end: usize, color: usize, num_colors: usize) -> Option<usize> {
+        let end = core::cmp::min(end, self.last_frame.number());
+        let mut frame_number = next_frame_with_color(core::cmp::max(start, self.base_frame), color, num_colors);
+        while frame_number < end {
+            let block_number = self.get_block_number(frame_number);
+            if self.block_is_used(block_number) {
+                let next_block = self.first_frame_in_block(block_number + 1).number();
+                frame_number = next_frame_with_color(next_block, color, num_colors);
+            } else if self.frame_is_used(frame_number) {
+                frame_number += num_colors;
+            } else {
+                return Some(frame_number)
+            }
+        }
+        None
+    }
+
+    /// Returns the number of the last free frame below `end`, clamped to managed memory
+    fn find_last_free_frame_before(&self, end: usize) -> Option<usize> {
+        let mut frame_number = core::cmp::min(end, self.last_frame.number());
+        while frame_number > self.base_frame {
+            let block_number = self.get_block_number(frame_number - 1);
+            let free_bits = !self.bitmap[block_number] & block_mask(0, (frame_number - 1) % BITS_PER_BLOCK + 1);
+            if free_bits == 0 {
+                frame_number = self.first_frame_in_block(block_number).number();
+            } else {
+                return Some(self.last_frame_in_block(block_number).number()
+                            - free_bits.leading_zeros() as usize)
+            }
+        }
+        None
+    }
+
+    /// Returns the number of the first frame of a run of `count` free frames
+    /// which starts at a frame number that is a multiple of `align`
+    fn find_free_run(&self, count: usize, align: usize) -> Option<usize> {
+        let last_frame_number = self.last_frame.number();
+        self.find_free_run_between(self.base_frame, last_frame_number, count, align)
+    }
+
+    /// Like `find_free_run`, but the run has to lie in `[start, end)`
+    fn find_free_run_between(&self, start: usize, end: usize, count: usize, align: usize) -> Option<usize> {
+        let end = core::cmp::min(end, self.last_frame.number());
+        let mut run_start = round_up(core::cmp::max(start, self.base_frame), align);
+        let mut frame_number = run_start;
+        // a search starting inside a block reads it without reaching its first frame
+        #[cfg(test)]
+        {
+            if (run_start - self.base_frame) % BITS_PER_BLOCK != 0 {
+                self.count_scanned_block();
+            }
+        }
+        while count <= end.saturating_sub(run_start) {
+            if frame_number == run_start + count {
+                return Some(run_start)
+            }
+            #[cfg(test)]
+            self.count_block_start(frame_number);
+
+            if self.frame_is_used(frame_number) {
+                // skip to the next aligned candidate past the used frame (or its whole block)
+                let block_number = self.get_block_number(frame_number);
+                let next_candidate = if self.block_is_used(block_number) {
+                    self.first_frame_in_block(block_number + 1).number()
+                } else {
+                    frame_number + 1
+                };
+                run_start = round_up(next_candidate, align);
+                frame_number = run_start;
+            } else {
+                frame_number += 1;
+            }
+        }
+        None
+    }
+
+    /// Length in frames of the longest run of free frames, e.g. to decide
+    /// whether a huge page can be backed before trying to allocate it
+    pub fn largest_free_run(&self) -> usize {
+        let last_frame_number = self.last_frame.number();
+        let mut largest_run = 0;
+        let mut run_length = 0;
+        let mut frame_number = self.base_frame;
+        while frame_number < last_frame_number {
+            let block_number = self.get_block_number(frame_number);
+            if frame_number % BITS_PER_BLOCK == 0 && self.block_is_used(block_number) {
+                run_length = 0;
+                frame_number += BITS_PER_BLOCK;
+            } else if self.frame_is_used(frame_number) {
+                run_length = 0;
+                frame_number += 1;
+            } else {
+                run_length += 1;
+                largest_run = core::cmp::max(largest_run, run_length);
+                frame_number += 1;
+            }
+        }
+        largest_run
+    }
+
+    /// Fills `buckets[k]` with the number of free runs whose length lies in
+    /// `[2^k, 2^(k+1))`, to see how fragmented memory is
+    pub fn free_run_histogram(&self, buckets: &mut [usize; 32]) {
+        for bucket in buckets.iter_mut() {
+            *bucket = 0;
+        }
+        let last_frame_number = self.last_frame.number();
+        let mut run_length = 0;
+        let mut frame_number = self.base_frame;
+        while frame_number < last_frame_number {
+            let block_number = self.get_block_number(frame_number);
+            if frame_number % BITS_PER_BLOCK == 0 && self.block_is_used(block_number) {
+                count_free_run(buckets, run_length);
+                run_length = 0;
+                frame_number += BITS_PER_BLOCK;
+            } else if self.frame_is_used(frame_number) {
+                count_free_run(buckets, run_length);
+                run_length = 0;
+                frame_number += 1;
+            } else {
+                run_length += 1;
+                frame_number += 1;
+            }
+        }
+        count_free_run(buckets, run_length);
+    }
+
+    /// Returns the number of the first frame of the smallest free run of at least `count` frames
+    fn find_best_fit_run(&self, count: usize) -> Option<usize> {
+        let last_frame_number = self.last_frame.number();
+        let mut best_run: Option<(usize, usize)> = None;
+        let mut frame_number = self.base_frame;
+        while frame_number < last_frame_number {
+            #[cfg(test)]
+            self.count_block_start(frame_number);
+            if self.frame_is_used(frame_number) {
+                let block_number = self.get_block_number(frame_number);
+                frame_number = if self.block_is_used(block_number) {
+                    self.first_frame_in_block(block_number + 1).number()
+                } else {
+                    frame_number + 1
+                };
+                continue;
+            }
+
+            let run_start = frame_number;
+            while frame_number < last_frame_number && !self.frame_is_used(frame_number) {
+                frame_number += 1;
+                #[cfg(test)]
+                self.count_block_start(frame_number);
+            }
+            let run_length = frame_number - run_start;
+            if run_length >= count && best_run.map_or(true, |(_, best_length)| run_length < best_length) {
+                best_run = Some((run_start, run_length));
+                if run_length == count {
+                    break;
+                }
+            }
+        }
+        best_run.map(|(run_start, _)| run_start)
+    }
+
+    #[cfg(test)]
+    fn count_scanned_block(&self) {
+        self.scanned_blocks.set(self.scanned_blocks.get() + 1);
+    }
+
+    /// Frame by frame searches read a new block whenever they reach its first frame
+    #[cfg(test)]
+    fn count_block_start(&self, frame_number: usize) {
+        if (frame_number - self.base_frame) % BITS_PER_BLOCK == 0 {
+            self.count_scanned_block();
+        }
+    }
+
+    pub fn first_frame_in_block(&self, block_number: usize) -> Frame {
+        Frame::from_number(self.base_frame + block_number * BITS_PER_BLOCK)
+    }
+
+    pub fn last_frame_in_block(&self, block_number: usize) -> Frame {
+        Frame::from_number(self.base_frame + block_number * BITS_PER_BLOCK + BITS_PER_BLOCK - 1)
+    }
+
+    /// `frame_number` must not be below the base frame
+    pub fn get_block_number(&self, frame_number: usize) -> usize {
+        (frame_number - self.base_frame) / BITS_PER_BLOCK
+    }
+
+    /// Index of the frame's bit in the bitmaps
+    fn bit_index(&self, frame_number: usize) -> usize {
+        frame_number - self.base_frame
+    }
+
+    /// Marks every bit from `last_frame` on as used, so that the block which
+    /// covers the end of memory reads as full once its managed frames are used,
+    /// and no search ever finds a frame beyond managed memory
+    fn mark_unmanaged_tail_used(&mut self) {
+        let first_bit = self.bit_index(self.last_frame.number());
+        let first_block = first_bit / BITS_PER_BLOCK;
+        if first_block < self.bitmap.len() {
+            self.bitmap[first_block] |= core::usize::MAX << (first_bit % BITS_PER_BLOCK);
+            for block in self.bitmap[first_block + 1..].iter_mut() {
+                *block = core::usize::MAX;
+            }
+        }
+    }
+
+    pub fn block_is_used(&self, index: usize) -> bool {
+        self.bitmap[index] == core::usize::MAX
+    }
+
+    /// Is the frame part of managed memory, i.e. can it be allocated or freed?
+    pub fn contains(&self, frame: Frame) -> bool {
+        self.frame_in_range(frame.number())
+    }
+
+    /// Like `frame_is_used`, but `InvalidRange` for frames outside of managed memory,
+    /// e.g. a bogus address handed over by a device
+    pub fn try_frame_is_used(&self, frame: Frame) -> Result<bool, FrameAllocError> {
+        if self.contains(frame) {
+            Ok(self.frame_is_used(frame.number()))
+        } else {
+            Err(FrameAllocError::InvalidRange)
+        }
+    }
+
+    /// False for frames outside of the bitmap
+    pub fn frame_is_used(&self, index: usize) -> bool {
+        index >= self.base_frame && self.bit_index(index) < self.bitmap.len() * BITS_PER_BLOCK
+            && bit_is_set(self.bitmap, self.bit_index(index))
+    }
+
+    /// False for frames outside of the bitmap
+    pub fn frame_is_reserved(&self, index: usize) -> bool {
+        index >= self.base_frame && self.bit_index(index) < self.reserved.len() * BITS_PER_BLOCK
+            && bit_is_set(self.reserved, self.bit_index(index))
+    }
+
+    /// Marks every frame that is not part of a usable memory area as used.
+    /// `MemoryAreaIter` only yields areas of type 1 (available RAM), so areas
+    /// reported as reserved, ACPI or NVS are never freed here. Starting with
+    /// everything used copes with areas which overlap or are out of order.
+    fn map_memory_areas<I>(&mut self, memory_areas: I) -> Result<(), FrameAllocError>
+        where I: Iterator<Item = (usize, usize)>
+    {
+        // memory beyond what the bitmap can track is never used, and the last
+        // bit of the bitmap is kept for the frame after managed memory
+        let bitmap_frames = self.bitmap.len() * BITS_PER_BLOCK;
+        assert!(bitmap_frames > 0, "Bitmap used by frame allocator is empty");
+        let capacity_end = self.base_frame + bitmap_frames - 1;
+        for block in self.bitmap.iter_mut() {
+            *block = core::usize::MAX;
+        }
+        self.last_frame = Frame::from_number(capacity_end);
+        self.free_frames = 0;
+
+        // only frames which lie entirely inside an area are usable
+        let capacity = FrameRange::new(Frame::from_number(self.base_frame), Frame::from_number(capacity_end));
+        let mut last_area_end = None;
+        for (area_start, area_end) in memory_areas {
+            last_area_end = Some(core::cmp::max(last_area_end.unwrap_or(0), area_end));
+            let area = FrameRange::new(Frame::from_number(align_up(area_start, PAGE_SIZE) / PAGE_SIZE),
+                                       Frame::from_number(area_end / PAGE_SIZE));
+            if let Some(usable) = area.intersection(&capacity) {
+                for frame in usable {
+                    self.set_used(frame.number(), false);
+                }
+            }
+        }
+        let last_area_end = last_area_end.ok_or(FrameAllocError::NoMemoryMap)?;
+
+        // no area reaches beyond the frame containing the highest end, so
+        // shrinking managed memory to it leaves every freed frame inside
+        let last_frame_number = Frame::checked_containing_address(last_area_end)
+                                      .map_or(capacity_end, |frame| core::cmp::min(frame.number(), capacity_end));
+        let last_frame_number = core::cmp::max(last_frame_number, self.base_frame);
+        self.last_frame = Frame::from_number(last_frame_number);
+        self.mark_unmanaged_tail_used();
+        self.total_frames = last_frame_number - self.base_frame;
+        self.high_frame = Frame::from_number(last_frame_number);
+        self.lowest_free_hint = core::usize::MAX;
+        Ok(())
+    }
+
+    fn map_kernel(&mut self, kernel: &FrameRange) -> usize {
+        self.reserve_frames(kernel.clone())
+    }
+
+    /// Only reserves the frames which `map_kernel` has not reserved already
+    fn map_multiboot(&mut self, multiboot: &FrameRange, kernel: &FrameRange) -> usize {
+        let (below_kernel, above_kernel) = multiboot.difference(kernel);
+        below_kernel.map_or(0, |frames| self.reserve_frames(frames))
+            + above_kernel.map_or(0, |frames| self.reserve_frames(frames))
+    }
+
+    /// Marks every frame containing an address in `start..=end` as used and reserved,
+    /// e.g. for MMIO regions discovered after the allocator was created. Unlike
+    /// `reserve_range` this also takes frames which are already used.
+    /// Returns how many of the frames were free before.
+    pub fn reserve_region(&mut self, start: PhysicalAddress, end: PhysicalAddress) -> usize {
+        self.reserve_frames(FrameRange::containing(start, end))
+    }
+
+    /// `reserve_region` for the regular frames of the huge frames `start..end`,
+    /// e.g. to back huge pages the bootloader already mapped
+    pub fn reserve_huge_frames(&mut self, start: HugeFrame, end: HugeFrame) -> usize {
+        self.reserve_frames(HugeFrame::split_range(start, end))
+    }
+
+    /// `reserve_region` for the managed part of `frames`
+    fn reserve_frames(&mut self, frames: FrameRange) -> usize {
+        let managed = FrameRange::new(Frame::from_number(self.base_frame), self.last_frame);
+        let frames = match frames.intersection(&managed) {
+            Some(frames) => frames,
+            None => return 0,
+        };
+        let mut newly_used = 0;
+        for frame in frames {
+            if !self.claim_frame(frame) {
+                newly_used += 1;
+            }
+            let bit = self.bit_index(frame.number());
+            set_bit(self.reserved, bit, true);
+        }
+        newly_used
+    }
+
+    /// Marks the frames containing `start_addr..=end_addr` as used and reserved,
+    /// so that they are not freed until `release_range` is called. Fails without
+    /// reserving anything if any of the frames is not free.
+    pub fn reserve_range(&mut self, start_addr: PhysicalAddress, end_addr: PhysicalAddress) -> Result<(), FrameAllocError> {
+        let (start, end) = self.managed_frames_containing(start_addr, end_addr)?;
+        if (start..end + 1).any(|frame_number| self.frame_is_used(frame_number)) {
+            return Err(FrameAllocError::AlreadyAllocated);
+        }
+        for frame_number in start..end + 1 {
+            self.set_used(frame_number, true);
+            let bit = self.bit_index(frame_number);
+            set_bit(self.reserved, bit, true);
+        }
+        Ok(())
+    }
+
+    /// Makes frame 0 available again for boot code which really needs it, e.g.
+    /// for the real mode IVT. Fails with `NotReserved` if frame 0 was not free
+    /// when the allocator was created, or was released already.
+    pub fn release_null_frame(&mut self) -> Result<(), FrameAllocError> {
+        if !self.null_frame_reserved {
+            return Err(FrameAllocError::NotReserved);
+        }
+        let null_frame = PhysicalAddress::new(0);
+        self.release_range(null_frame, null_frame)?;
+        self.null_frame_reserved = false;
+        Ok(())
+    }
+
+    /// Frees the frames containing `start_addr..=end_addr` which were reserved with
+    /// `reserve_range`. Fails without freeing anything if any of them is not reserved.
+    pub fn release_range(&mut self, start_addr: PhysicalAddress, end_addr: PhysicalAddress) -> Result<(), FrameAllocError> {
+        let (start, end) = self.managed_frames_containing(start_addr, end_addr)?;
+        if !(start..end + 1).all(|frame_number| self.frame_is_reserved(frame_number)) {
+            return Err(FrameAllocError::NotReserved);
+        }
+        for frame_number in start..end + 1 {
+            let bit = self.bit_index(frame_number);
+            set_bit(self.reserved, bit, false);
+            self.set_used(frame_number, false);
+        }
+        Ok(())
+    }
+
+    /// Numbers of the first and last frame containing `start_addr..=end_addr`,
+    /// if they are all part of managed memory
+    fn managed_frames_containing(&self, start_addr: PhysicalAddress, end_addr: PhysicalAddress) -> Result<(usize, usize), FrameAllocError> {
+        let start = Frame::containing_address(start_addr).number();
+        let end = Frame::containing_address(end_addr).number();
+        if start > end || !self.frame_in_range(start) || !self.frame_in_range(end) {
+            Err(FrameAllocError::InvalidRange)
+        } else {
+            Ok((start, end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::FrameManager;
+    use std::boxed::Box;
+    use std::collections::HashSet;
+    use std::vec::Vec;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use spin::Mutex;
+
+    fn reserved_bitmap(len: usize) -> &'static mut [usize] {
+        Box::leak(vec![0; len].into_boxed_slice())
+    }
+
+    /// Frame 0 holds both the kernel and the multiboot structure
+    fn allocator<'a>(bitmap: &'a mut [usize], areas: &[(usize, usize)]) -> BitmapFrameAllocator<'a> {
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        BitmapFrameAllocator::from_areas(bitmap, reserved, zero, zero, zero, zero, areas.to_vec().into_iter()).unwrap()
+    }
+
+    #[test]
+    fn reserved_areas_are_used() {
+        let mut bitmap = vec![0; 64];
+        // available: 0x10000-0x9f000 and 0x100000-0x200000;
+        // reserved: 0x0-0x10000 and 0x9f000-0x100000
+        let allocator = allocator(&mut bitmap, &[(0x10000, 0x9f000), (0x100000, 0x200000)]);
+
+        assert!((0x1..0x10).all(|n| allocator.frame_is_used(n)));
+        assert!((0x10..0x9f).all(|n| !allocator.frame_is_used(n)));
+        assert!((0x9f..0x100).all(|n| allocator.frame_is_used(n)));
+        assert!((0x100..0x200).all(|n| !allocator.frame_is_used(n)));
+    }
+
+    #[test]
+    fn reserve_region_marks_frames_used() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+
+        // VGA text buffer
+        assert_eq!(allocator.reserve_region(PhysicalAddress::new(0xb8000), PhysicalAddress::new(0xc0000 - 1)), 8);
+        assert!((0xb8..0xc0).all(|n| allocator.frame_is_used(n)));
+        assert!(!allocator.frame_is_used(0xb7) && !allocator.frame_is_used(0xc0));
+        assert_eq!(allocator.free_frame_count(), free_before - 8);
+    }
+
+    #[test]
+    fn reserve_region_counts_newly_used_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+
+        assert_eq!(allocator.reserve_region(PhysicalAddress::new(0x10000), PhysicalAddress::new(0x13fff)), 4);
+        assert_eq!(allocator.reserve_region(PhysicalAddress::new(0x10000), PhysicalAddress::new(0x13fff)), 0);
+        assert_eq!(allocator.reserve_region(PhysicalAddress::new(0x12000), PhysicalAddress::new(0x15fff)), 2);
+        // frame 0 holds the kernel
+        assert_eq!(allocator.map_kernel(&FrameRange::containing(PhysicalAddress::new(0), PhysicalAddress::new(0x1fff))), 1);
+    }
+
+    #[test]
+    fn kernel_above_managed_memory_is_rejected() {
+        let mut bitmap = vec![0; 32];
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        let result = BitmapFrameAllocator::from_areas(&mut bitmap, reserved, PhysicalAddress::new(0x100000),
+                                                      PhysicalAddress::new(0x180fff), zero, zero,
+                                                      vec![(0, 0x180000)].into_iter());
+        assert_eq!(result.err(), Some(FrameAllocError::InvalidRange));
+
+        let mut bitmap = vec![0; 32];
+        let reserved = reserved_bitmap(bitmap.len());
+        let result = BitmapFrameAllocator::from_areas(&mut bitmap, reserved, zero, zero, PhysicalAddress::new(0x200000),
+                                                      PhysicalAddress::new(0x200fff), vec![(0, 0x180000)].into_iter());
+        assert_eq!(result.err(), Some(FrameAllocError::InvalidRange));
+
+        // the kernel may end right at the top of managed memory
+        let mut bitmap = vec![0; 32];
+        let reserved = reserved_bitmap(bitmap.len());
+        let allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved, PhysicalAddress::new(0x100000),
+                                                         PhysicalAddress::new(0x17ffff), zero, zero,
+                                                         vec![(0, 0x180000)].into_iter()).unwrap();
+        assert!(allocator.frame_is_reserved(0x17f));
+    }
+
+    #[test]
+    fn claiming_a_frame_twice_reports_the_collision() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+        let framebuffer = Frame::containing_address(PhysicalAddress::new(0xa0000));
+
+        assert!(!allocator.claim_frame(framebuffer));
+        assert!(allocator.claim_frame(framebuffer));
+        assert!(allocator.frame_is_used(framebuffer.number()));
+        assert_eq!(allocator.free_frame_count(), free_before - 1);
+        assert!(allocator.claim_frame(Frame::containing_address(PhysicalAddress::new(0x100000000))));
+    }
+
+    #[test]
+    fn frames_past_managed_memory_are_not_claimed() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+
+        assert!(allocator.claim_frame(allocator.last_frame));
+        assert_eq!(allocator.reserve_region(PhysicalAddress::new(0xfe000), PhysicalAddress::new(0x101fff)), 2);
+        assert!(!allocator.frame_is_reserved(allocator.last_frame.number()));
+        assert_eq!(allocator.free_frame_count(), free_before - 2);
+    }
+
+    #[test]
+    fn partially_covered_last_block_fills_up() {
+        let mut bitmap = vec![0; 4];
+        // ends in the middle of both a bitmap block and a frame
+        let mut allocator = allocator(&mut bitmap, &[(0, (BITS_PER_BLOCK + 10) * PAGE_SIZE + 0x800)]);
+        assert_eq!(allocator.last_frame.number(), BITS_PER_BLOCK + 10);
+        assert!((BITS_PER_BLOCK + 10..4 * BITS_PER_BLOCK).all(|n| allocator.frame_is_used(n)));
+
+        let free_frames = allocator.free_frame_count();
+        let mut allocated = 0;
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(frame < allocator.last_frame, "{} is beyond managed memory", frame);
+            allocated += 1;
+        }
+        assert_eq!(allocated, free_frames);
+        assert!(allocator.block_is_used(0) && allocator.block_is_used(1));
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.try_deallocate_frame(allocator.last_frame), Err(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    fn memory_beyond_bitmap_is_ignored() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000), (0x180000, 0x200000)]);
+        assert_eq!(allocator.last_frame.number(), 4 * BITS_PER_BLOCK - 1);
+
+        allocator.reserve_region(PhysicalAddress::new(0xfee00000), PhysicalAddress::new(0xfee00fff));
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(frame.number() < 4 * BITS_PER_BLOCK - 1);
+        }
+        assert_eq!(allocator.allocate_frame_at(Frame::containing_address(PhysicalAddress::new(0x180000))),
+                   Err(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    fn memory_ending_at_4_gib_fills_the_bitmap() {
+        let mut bitmap = vec![0; ARRAY_SIZE];
+        let allocator = allocator(&mut bitmap, &[(0, MAX_MEM_SIZE)]);
+
+        assert_eq!(allocator.last_frame.number(), NUM_OF_FRAMES - 1);
+        assert_eq!(allocator.free_frame_count(), NUM_OF_FRAMES - 2);
+        assert!(allocator.frame_is_used(NUM_OF_FRAMES - 1));
+    }
+
+    #[test]
+    fn memory_beyond_4_gib_is_clamped() {
+        let mut bitmap = vec![0; ARRAY_SIZE];
+        {
+            let allocator = allocator(&mut bitmap, &[(0, 0x100000), (MAX_MEM_SIZE - 0x100000, 2 * MAX_MEM_SIZE)]);
+            assert_eq!(allocator.last_frame.number(), NUM_OF_FRAMES - 1);
+            assert_eq!(allocator.free_frame_count(), 0xff + 0xff);
+        }
+        let allocator = allocator(&mut bitmap, &[(0, core::usize::MAX)]);
+        assert_eq!(allocator.last_frame.number(), NUM_OF_FRAMES - 1);
+        assert_eq!(allocator.free_frame_count(), NUM_OF_FRAMES - 2);
+    }
+
+    #[test]
+    fn larger_bitmap_covers_memory_above_4_gib() {
+        let mut bitmap = vec![0; 2 * ARRAY_SIZE + 1];
+        let high_memory = 0x100000000;
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000), (high_memory, high_memory + 0x100000)]);
+
+        assert!(allocator.frame_is_used(NUM_OF_FRAMES - 1));
+        let frame = allocator.allocate_frame_at(Frame::containing_address(PhysicalAddress::new(high_memory))).unwrap();
+        assert_eq!(frame.start_address(), high_memory);
+        assert_eq!(allocator.free_frame_count(), 0xff + 0xff);
+    }
+
+    #[test]
+    fn reserve_range_fails_on_allocated_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        allocator.allocate_frame_at(Frame::from_number(0x9f)).unwrap();
+        let free_before = allocator.free_frame_count();
+
+        assert_eq!(allocator.reserve_range(PhysicalAddress::new(0x9a000), PhysicalAddress::new(0x9ffff)), Err(FrameAllocError::AlreadyAllocated));
+        assert!((0x9a..0x9f).all(|n| !allocator.frame_is_used(n) && !allocator.frame_is_reserved(n)));
+        assert_eq!(allocator.reserve_range(PhysicalAddress::new(0xff000), PhysicalAddress::new(0x100fff)), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.free_frame_count(), free_before);
+    }
+
+    #[test]
+    fn reserved_frames_are_only_freed_by_release() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+
+        // EBDA
+        assert_eq!(allocator.reserve_range(PhysicalAddress::new(0x9a000), PhysicalAddress::new(0x9ffff)), Ok(()));
+        assert!((0x9a..0xa0).all(|n| allocator.frame_is_used(n) && allocator.frame_is_reserved(n)));
+        assert_eq!(allocator.free_frame_count(), free_before - 6);
+        assert_eq!(allocator.try_deallocate_frame(Frame::from_number(0x9c)), Err(FrameAllocError::Reserved));
+        let range = Frame::range_inclusive(Frame::from_number(0x99), Frame::from_number(0x9a));
+        assert!(allocator.deallocate_frames(range).is_err());
+        assert!(allocator.frame_is_used(0x9c));
+
+        assert_eq!(allocator.release_range(PhysicalAddress::new(0x99000), PhysicalAddress::new(0x9ffff)), Err(FrameAllocError::NotReserved));
+        assert_eq!(allocator.release_range(PhysicalAddress::new(0x9a000), PhysicalAddress::new(0x9ffff)), Ok(()));
+        assert!((0x9a..0xa0).all(|n| !allocator.frame_is_used(n) && !allocator.frame_is_reserved(n)));
+        assert_eq!(allocator.free_frame_count(), free_before);
+        assert_eq!(allocator.allocate_frame_at(Frame::from_number(0x9c)), Ok(Frame::from_number(0x9c)));
+    }
+
+    #[test]
+    fn overlapping_and_unsorted_areas() {
+        let mut bitmap = vec![0; 32];
+        // out of order, the first two overlap, the last one starts mid-frame
+        let allocator = allocator(&mut bitmap, &[(0x100000, 0x200000), (0x10000, 0x30000), (0x20000, 0x40000),
+                                                 (0x50800, 0x60000)]);
+
+        assert!((0x1..0x10).all(|n| allocator.frame_is_used(n)));
+        assert!((0x10..0x40).all(|n| !allocator.frame_is_used(n)));
+        assert!((0x40..0x51).all(|n| allocator.frame_is_used(n)));
+        assert!((0x51..0x60).all(|n| !allocator.frame_is_used(n)));
+        assert!((0x60..0x100).all(|n| allocator.frame_is_used(n)));
+        assert!((0x100..0x200).all(|n| !allocator.frame_is_used(n)));
+        assert_eq!(allocator.free_frame_count(), 0x30 + 0xf + 0x100);
+    }
+
+    #[test]
+    fn empty_memory_map_is_rejected() {
+        let mut bitmap = vec![0; 32];
+        let zero = PhysicalAddress::new(0);
+        let result = BitmapFrameAllocator::from_areas(&mut bitmap, reserved_bitmap(32), zero, zero, zero, zero,
+                                                      Vec::new().into_iter());
+        assert_eq!(result.err(), Some(FrameAllocError::NoMemoryMap));
+    }
+
+    #[test]
+    fn last_frame_uses_highest_end_address() {
+        let mut bitmap = vec![0; 64];
+        // the area with the highest base address ends below the first one
+        let allocator = allocator(&mut bitmap, &[(0, 0x200000), (0x100000, 0x180000)]);
+
+        assert_eq!(allocator.last_frame.number(), 0x200);
+        assert!(!allocator.frame_is_used(0x1ff));
+    }
+
+    #[test]
+    fn try_allocate_frame_reports_out_of_memory() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x40000)]);
+
+        for frame_number in 1..BITS_PER_BLOCK {
+            assert_eq!(allocator.try_allocate_frame().unwrap().number(), frame_number);
+        }
+        assert_eq!(allocator.try_allocate_frame().unwrap_err(), FrameAllocError::OutOfMemory);
+    }
+
+    #[test]
+    fn allocate_frame_at_claims_specific_frame() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let trampoline = Frame::containing_address(PhysicalAddress::new(0x8000));
+
+        assert_eq!(allocator.allocate_frame_at(trampoline).unwrap().number(), 8);
+        assert!(allocator.frame_is_used(8));
+        assert_eq!(allocator.allocate_frame_at(trampoline).unwrap_err(), FrameAllocError::AlreadyAllocated);
+        assert_eq!(allocator.allocate_frame_at(Frame::containing_address(PhysicalAddress::new(0x100000))).unwrap_err(),
+                   FrameAllocError::InvalidRange);
+    }
+
+    #[test]
+    fn double_free_is_detected() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let frame = allocator.allocate_frame().unwrap();
+
+        assert_eq!(allocator.try_deallocate_frame(frame), Ok(()));
+        assert_eq!(allocator.try_deallocate_frame(frame), Err(FrameAllocError::DoubleFree));
+    }
+
+    #[test]
+    fn shared_frame_is_freed_by_last_deallocation() {
+        let mut bitmap = vec![0; 1];
+        let mut refcounts = vec![0xff; 16];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        allocator.track_refcounts(&mut refcounts);
+        let free_frames = allocator.free_frame_count();
+
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.increment_ref(frame);
+        allocator.deallocate_frame(frame);
+        assert!(allocator.frame_is_used(frame.number()));
+        allocator.deallocate_frame(frame);
+        assert!(!allocator.frame_is_used(frame.number()));
+        assert_eq!(allocator.free_frame_count(), free_frames);
+
+        // a frame allocated again starts over with one reference
+        let frame = allocator.allocate_frame_at(frame).unwrap();
+        allocator.deallocate_frame(frame);
+        assert!(!allocator.frame_is_used(frame.number()));
+    }
+
+    #[test]
+    fn shared_frame_is_zeroed_by_last_deallocation() {
+        static ZEROED: AtomicUsize = AtomicUsize::new(0);
+        fn zeroer(_frame: &Frame) {
+            ZEROED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut bitmap = vec![0; 1];
+        let mut refcounts = vec![0; 16];
+        let mut zeroed = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+        allocator.track_refcounts(&mut refcounts);
+        allocator.track_zeroed_frames(&mut zeroed);
+
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.increment_ref(frame);
+        allocator.deallocate_frame_zeroed(frame);
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 0);
+        assert!(allocator.frame_is_used(frame.number()));
+        allocator.deallocate_frame_zeroed(frame);
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 1);
+        assert!(!allocator.frame_is_used(frame.number()));
+    }
+
+    #[test]
+    #[should_panic(expected = "frame deallocation failed: Err(InvalidRange)")]
+    fn zeroed_free_below_base_frame_panics() {
+        let mut bitmap = vec![0; 2];
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        let mut allocator = BitmapFrameAllocator::from_areas_with_base(&mut bitmap, reserved, Frame::from_number(256),
+                                                                       zero, zero, zero, zero,
+                                                                       vec![(0, 0x200000)].into_iter()).unwrap();
+        allocator.deallocate_frame_zeroed(Frame::from_number(255));
+    }
+
+    #[test]
+    #[should_panic(expected = "frame deallocation failed: Err(DoubleFree)")]
+    fn double_free_panics() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let frame = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    #[test]
+    fn out_of_range_frames_are_rejected() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let beyond = 32 * BITS_PER_BLOCK + 100;
+
+        assert_eq!(allocator.try_deallocate_frame(Frame::from_number(beyond)), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.allocate_frame_at(Frame::from_number(beyond)).unwrap_err(), FrameAllocError::InvalidRange);
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(beyond), Frame::from_number(beyond + 10)),
+                   Err(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    #[should_panic(expected = "frame 0x100 at 0x100000 exceeds managed memory")]
+    fn deallocate_out_of_range_frame_panics() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        allocator.deallocate_frame(Frame::from_number(256));
+    }
+
+    #[test]
+    fn allocate_frame_in_near_full_blocks() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        // leave every 29th frame free
+        let last_frame_number = allocator.last_frame.number();
+        for frame_number in (0..last_frame_number).filter(|n| n % 29 != 0) {
+            allocator.set_used(frame_number, true);
+        }
+        allocator.set_used(0, true);
+
+        // the old per-bit search
+        let expected: Vec<usize> = (0..last_frame_number).filter(|&n| !allocator.frame_is_used(n)).collect();
+        let allocated: Vec<usize> = (0..expected.len()).map(|_| allocator.allocate_frame().unwrap().number())
+                                                       .collect();
+        assert_eq!(allocated, expected);
+        assert!(allocator.allocate_frame().is_none());
+    }
+
+    #[test]
+    fn exhausted_memory_fails_without_scanning() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        while allocator.allocate_frame().is_some() {}
+
+        allocator.scanned_blocks.set(0);
+        assert!(allocator.allocate_frame().is_none());
+        assert_eq!(allocator.scanned_blocks.get(), 0);
+    }
+
+    #[test]
+    fn allocate_after_exhaustion_returns_freed_frame() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        while allocator.allocate_frame().is_some() {}
+        assert_eq!(allocator.next_frame.number(), 0);
+        assert!(!allocator.second_scan);
+
+        allocator.deallocate_frame(Frame::from_number(42));
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 42);
+    }
+
+    #[test]
+    fn freed_low_frame_is_found_without_wrapping() {
+        let mut bitmap = vec![0; 128];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x1000000)]);
+        while allocator.free_frame_count() > 0 {
+            allocator.allocate_frame().unwrap();
+        }
+        allocator.next_frame = Frame::from_number(BITS_PER_BLOCK);
+
+        allocator.deallocate_frame(Frame::from_number(5));
+        allocator.scanned_blocks.set(0);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 5);
+        assert_eq!(allocator.scanned_blocks.get(), 1);
+    }
+
+    #[test]
+    fn second_scan_stops_at_first_scan_start() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        while allocator.allocate_frame().is_some() {}
+        allocator.deallocate_frame(Frame::from_number(100));
+        allocator.next_frame = Frame::from_number(150);
+
+        allocator.scanned_blocks.set(0);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 100);
+        assert!(allocator.scanned_blocks.get() <= allocator.last_frame.number() / BITS_PER_BLOCK + 1);
+    }
+
+    #[test]
+    fn allocate_frame_zeroed_zeroes_frame() {
+        static ZEROED: AtomicUsize = AtomicUsize::new(0);
+        fn zeroer(frame: &Frame) {
+            ZEROED.store(frame.number(), Ordering::SeqCst);
+        }
+
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+
+        let frame = allocator.allocate_frame_zeroed().unwrap();
+        assert_eq!(ZEROED.load(Ordering::SeqCst), frame.number());
+    }
+
+    #[test]
+    fn zeroed_and_plain_allocations_share_an_allocator() {
+        // physical memory is a heap buffer, mapped at the address stored here
+        static PHYSICAL_MEMORY_OFFSET: AtomicUsize = AtomicUsize::new(0);
+        fn zeroer(frame: &Frame) {
+            unsafe {
+                ptr::write_bytes(frame.as_ptr_offset(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst)), 0, PAGE_SIZE);
+            }
+        }
+
+        let mut memory = vec![0xffu8; 4 * PAGE_SIZE];
+        PHYSICAL_MEMORY_OFFSET.store(memory.as_mut_ptr() as usize, Ordering::SeqCst);
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 4 * PAGE_SIZE)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+
+        let zeroed = allocator.allocate_frame_zeroed().unwrap();
+        let plain = allocator.allocate_frame().unwrap();
+        let frame_memory = |frame: Frame| &memory[frame.start_address()..frame.start_address() + PAGE_SIZE];
+        assert!(frame_memory(zeroed).iter().all(|&byte| byte == 0));
+        assert!(frame_memory(plain).iter().all(|&byte| byte == 0xff));
+
+        // frames kept back for critical allocations are not handed out zeroed either
+        let free_frames = allocator.free_frame_count();
+        allocator.set_reserve(free_frames);
+        assert_eq!(allocator.allocate_frame_zeroed(), None);
+    }
+
+    #[test]
+    fn frames_zeroed_on_free_are_not_zeroed_again() {
+        static ZEROED: AtomicUsize = AtomicUsize::new(0);
+        fn zeroer(_frame: &Frame) {
+            ZEROED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut bitmap = vec![0; 32];
+        let mut zeroed = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x40000)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+        allocator.track_zeroed_frames(&mut zeroed);
+        for _ in 1..BITS_PER_BLOCK {
+            allocator.allocate_frame().unwrap();
+        }
+
+        allocator.deallocate_frame_zeroed(Frame::from_number(5));
+        allocator.deallocate_frame(Frame::from_number(6));
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 1);
+        assert_eq!(allocator.allocate_frame_zeroed().unwrap().number(), 5);
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 1);
+        assert_eq!(allocator.allocate_frame_zeroed().unwrap().number(), 6);
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn zero_on_alloc_zeroes_allocated_frames() {
+        // physical memory seen through a fake identity mapping
+        static mut MEMORY: [u8; 4 * PAGE_SIZE] = [0xff; 4 * PAGE_SIZE];
+        fn zeroer(frame: &Frame) {
+            unsafe {
+                ptr::write_bytes(MEMORY.as_mut_ptr().offset(frame.start_address() as isize), 0, PAGE_SIZE);
+            }
+        }
+        fn frame_memory(frame: &Frame) -> &'static [u8] {
+            unsafe { &MEMORY[frame.start_address()..frame.start_address() + PAGE_SIZE] }
+        }
+
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 4 * PAGE_SIZE)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert!(frame_memory(&frame).iter().all(|&byte| byte == 0xff));
+        allocator.set_zero_on_alloc(true);
+        let frame = allocator.allocate_frame().unwrap();
+        assert!(frame_memory(&frame).iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn frame_counts() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x64000)]);
+        assert_eq!(allocator.used_frame_count(), 1);
+        assert_eq!(allocator.free_frame_count(), 0x63);
+
+        for _ in 0..7 {
+            allocator.allocate_frame().unwrap();
+        }
+        assert_eq!(allocator.used_frame_count(), 8);
+        assert_eq!(allocator.used_frame_count() + allocator.free_frame_count(), 0x64);
+    }
+
+    #[test]
+    fn frame_counters_match_bitmap() {
+        fn used_by_popcount(allocator: &BitmapFrameAllocator) -> usize {
+            (0..allocator.last_frame.number()).filter(|&n| allocator.frame_is_used(n)).count()
+        }
+
+        let mut bitmap = vec![0; 32];
+        // kernel and multiboot overlap each other and a reserved gap
+        let mut allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved_bitmap(32),
+                                                             PhysicalAddress::new(0x9000), PhysicalAddress::new(0x14fff),
+                                                             PhysicalAddress::new(0x12000), PhysicalAddress::new(0x16fff),
+                                                             vec![(0x0, 0x10000), (0x13000, 0x300000)].into_iter())
+                                                             .unwrap();
+        assert_eq!(allocator.used_frame_count(), used_by_popcount(&allocator));
+        assert_eq!(allocator.total_memory_bytes(), 0x300000);
+
+        let mut frames: Vec<Frame> = (0..300).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert_eq!(allocator.used_frame_count(), used_by_popcount(&allocator));
+        for frame in frames.drain(100..) {
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.used_frame_count(), used_by_popcount(&allocator));
+
+        let mut batch: Vec<Frame> = (0..50).map(|_| Frame::from_number(0)).collect();
+        allocator.allocate_frames_into(&mut batch).unwrap();
+        let range = allocator.allocate_frames(70).unwrap();
+        assert_eq!(allocator.used_frame_count(), used_by_popcount(&allocator));
+        allocator.deallocate_range(range);
+        assert_eq!(allocator.used_frame_count(), used_by_popcount(&allocator));
+        assert_eq!(allocator.free_frame_count() + allocator.used_frame_count(), 0x300);
+    }
+
+    #[test]
+    fn free_frames_skips_allocated_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        for &frame_number in &[10, 63, 64] {
+            allocator.allocate_frame_at(Frame::from_number(frame_number)).unwrap();
+        }
+
+        let free: Vec<usize> = allocator.free_frames().map(|frame| frame.number()).collect();
+        assert!(!free.contains(&10) && !free.contains(&63) && !free.contains(&64));
+        assert!(free.contains(&9) && free.contains(&11) && free.contains(&62) && free.contains(&65));
+        assert_eq!(free.len(), allocator.free_frame_count());
+        assert_eq!(free.last(), Some(&(allocator.last_frame.number() - 1)));
+    }
+
+    #[test]
+    fn contiguous_run_straddles_block_boundary() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        for frame_number in 0..BITS_PER_BLOCK - 2 {
+            allocator.set_used(frame_number, true);
+        }
+
+        let frame = allocator.allocate_contiguous_frames(4).unwrap();
+        assert_eq!(frame.number(), BITS_PER_BLOCK - 2);
+        for frame_number in BITS_PER_BLOCK - 2..BITS_PER_BLOCK + 2 {
+            assert!(allocator.frame_is_used(frame_number));
+        }
+        assert!(!allocator.frame_is_used(BITS_PER_BLOCK + 2));
+
+        allocator.deallocate_contiguous_frames(frame, 4);
+        for frame_number in BITS_PER_BLOCK - 2..BITS_PER_BLOCK + 2 {
+            assert!(!allocator.frame_is_used(frame_number));
+        }
+    }
+
+    #[test]
+    fn contiguous_run_not_found() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        for frame_number in (0..allocator.last_frame.number()).filter(|n| n % 2 == 0) {
+            allocator.set_used(frame_number, true);
+        }
+
+        assert_eq!(allocator.allocate_contiguous_frames(2), Err(FrameAllocError::OutOfMemory));
+        let used: Vec<usize> = (0..allocator.last_frame.number())
+            .filter(|&n| allocator.frame_is_used(n)).collect();
+        assert_eq!(used.len(), allocator.last_frame.number() / 2);
+    }
+
+    #[test]
+    fn no_frame_is_handed_out_twice() {
+        let mut bitmap = vec![0; 64];
+        let mut allocator = allocator(&mut bitmap, &[(0, 64 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        let free_frames = allocator.free_frame_count();
+        let mut live = HashSet::new();
+
+        for round in 0..6 {
+            while let Some(frame) = allocator.allocate_frame() {
+                assert!(live.insert(frame), "{} was handed out twice", frame);
+            }
+            assert_eq!(allocator.allocate_contiguous_frames(1), Err(FrameAllocError::OutOfMemory));
+
+            let freed: Vec<Frame> = live.iter().cloned().filter(|frame| frame.number() % 3 == round % 3).collect();
+            for frame in freed {
+                live.remove(&frame);
+                allocator.deallocate_frame(frame);
+            }
+            assert_eq!(live.len() + allocator.free_frame_count(), free_frames);
+        }
+        assert!(live.len() > 1000);
+    }
+
+    #[test]
+    fn frame_0_is_never_allocated() {
+        let mut bitmap = vec![0; 2];
+        let reserved = reserved_bitmap(bitmap.len());
+        let mut allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved,
+                                                             PhysicalAddress::new(0x10000), PhysicalAddress::new(0x10fff),
+                                                             PhysicalAddress::new(0x11000), PhysicalAddress::new(0x11fff),
+                                                             vec![(0, 100 * PAGE_SIZE)].into_iter()).unwrap();
+        assert!(allocator.frame_is_used(0) && allocator.frame_is_reserved(0));
+
+        assert_eq!(allocator.allocate_frame_below(PAGE_SIZE), Err(FrameAllocError::OutOfMemory));
+        assert_ne!(allocator.allocate_frames_best_fit(15).unwrap().start.number(), 0);
+        assert_ne!(allocator.allocate_contiguous_frames(3).unwrap().number(), 0);
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.allocate_frame() {
+            frames.push(frame);
+        }
+        assert!(frames.iter().all(|frame| frame.number() != 0));
+        for frame in frames {
+            allocator.deallocate_frame(frame);
+        }
+        assert_ne!(allocator.allocate_frame().unwrap().number(), 0);
+
+        assert_eq!(allocator.release_null_frame(), Ok(()));
+        assert_eq!(allocator.release_null_frame(), Err(FrameAllocError::NotReserved));
+        assert_eq!(allocator.allocate_frame_below(PAGE_SIZE).unwrap().number(), 0);
+    }
+
+    #[test]
+    fn nonzero_frames_skip_released_frame_0() {
+        let mut bitmap = vec![0; 1];
+        let reserved = reserved_bitmap(bitmap.len());
+        let mut allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved,
+                                                             PhysicalAddress::new(0x8000), PhysicalAddress::new(0x8fff),
+                                                             PhysicalAddress::new(0x8000), PhysicalAddress::new(0x8fff),
+                                                             vec![(0, 4 * PAGE_SIZE), (0x8000, 0x9000)].into_iter()).unwrap();
+        assert_eq!(allocator.release_null_frame(), Ok(()));
+        allocator.set_alloc_policy(AllocPolicy::FirstFit);
+
+        assert_eq!(allocator.allocate_nonzero_frame().map(|frame| frame.number()), Some(1));
+        assert!(!allocator.frame_is_used(0));
+        assert_eq!(allocator.allocate_nonzero_frame().map(|frame| frame.number()), Some(2));
+        assert_eq!(allocator.allocate_nonzero_frame().map(|frame| frame.number()), Some(3));
+        assert_eq!(allocator.allocate_nonzero_frame(), None);
+        assert_eq!(allocator.free_frame_count(), 1);
+    }
+
+    #[test]
+    fn nonzero_frames_never_take_frame_0() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        fn count_hit() {
+            HITS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut bitmap = vec![0; 1];
+        let reserved = reserved_bitmap(bitmap.len());
+        let mut allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved,
+                                                             PhysicalAddress::new(0x8000), PhysicalAddress::new(0x8fff),
+                                                             PhysicalAddress::new(0x8000), PhysicalAddress::new(0x8fff),
+                                                             vec![(0, 4 * PAGE_SIZE), (0x8000, 0x9000)].into_iter()).unwrap();
+        assert_eq!(allocator.release_null_frame(), Ok(()));
+        // taking frame 0 on the way would drop the free count by two
+        let threshold = allocator.free_frame_count() - 2;
+        allocator.set_low_watermark(threshold, count_hit);
+
+        assert_eq!(allocator.allocate_nonzero_frame().map(|frame| frame.number()), Some(1));
+        assert_eq!(HITS.load(Ordering::SeqCst), 0);
+        assert_eq!(allocator.free_frame_count(), threshold + 1);
+
+        while allocator.allocate_nonzero_frame().is_some() {}
+        // frame 0 is the only free frame left and stays free
+        assert_eq!(allocator.free_frame_count(), 1);
+        assert!(!allocator.frame_is_used(0));
+    }
+
+    #[test]
+    fn used_frame_0_is_not_released() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        assert_eq!(allocator.release_null_frame(), Err(FrameAllocError::NotReserved));
+        assert!(allocator.frame_is_used(0));
+    }
+
+    /// Used frames counted the slow way, straight from the bitmap
+    fn count_used_frames(allocator: &BitmapFrameAllocator) -> usize {
+        let managed_bits = allocator.last_frame.number() - allocator.base_frame;
+        let full_blocks = managed_bits / BITS_PER_BLOCK;
+        allocator.bitmap[..full_blocks].iter().map(|block| block.count_ones() as usize).sum::<usize>()
+            + (allocator.bitmap[full_blocks] & block_mask(0, managed_bits % BITS_PER_BLOCK)).count_ones() as usize
+    }
+
+    #[test]
+    fn used_frame_count_matches_bitmap() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(0x10000, 0x9f000), (0xa0000, 200 * PAGE_SIZE)]);
+        assert_eq!(allocator.used_frame_count(), count_used_frames(&allocator));
+
+        let frames: Vec<Frame> = (0..20).map(|_| allocator.allocate_frame().unwrap()).collect();
+        let range = allocator.allocate_frames(30).unwrap();
+        let aligned = allocator.allocate_contiguous_aligned(5, 32).unwrap();
+        allocator.reserve_region(PhysicalAddress::new(0x9f000), PhysicalAddress::new(0xa2fff));
+        assert_eq!(allocator.used_frame_count(), count_used_frames(&allocator));
+
+        for frame in frames.into_iter().filter(|frame| frame.number() % 2 == 0) {
+            allocator.deallocate_frame(frame);
+        }
+        allocator.deallocate_range(range);
+        allocator.deallocate_contiguous_frames(aligned, 5);
+        assert_eq!(allocator.used_frame_count(), count_used_frames(&allocator));
+
+        while allocator.allocate_frame().is_some() {}
+        assert_eq!(allocator.used_frame_count(), count_used_frames(&allocator));
+        assert_eq!(allocator.free_frame_count(), 0);
+    }
+
+    /// Xorshift, so the random bitmaps are the same on every run
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// The lowest free frame at or after `cursor`, wrapping around once, looked up one bit at a time
+    fn first_free_frame_per_bit(used: &[bool], cursor: usize) -> Option<usize> {
+        (cursor..used.len()).chain(0..cursor).find(|&frame_number| !used[frame_number])
+    }
+
+    #[test]
+    fn word_search_matches_per_bit_search() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..20 {
+            let mut bitmap = vec![0; 8];
+            let mut allocator = allocator(&mut bitmap, &[(0, 8 * BITS_PER_BLOCK * PAGE_SIZE)]);
+            let last_frame_number = allocator.last_frame.number();
+            // mostly full, like a busy machine
+            for frame_number in 1..last_frame_number {
+                if next_random(&mut state) % 8 != 0 {
+                    allocator.set_used(frame_number, true);
+                }
+            }
+            let mut used = (0..last_frame_number).map(|n| allocator.frame_is_used(n)).collect::<Vec<_>>();
+
+            for _ in 0..20 {
+                let start = next_random(&mut state) as usize % last_frame_number;
+                let end = start + next_random(&mut state) as usize % (last_frame_number - start + 1);
+                assert_eq!(allocator.find_free_frame_between(start, end),
+                           (start..end).find(|&frame_number| !used[frame_number]));
+            }
+
+            let mut cursor = next_random(&mut state) as usize % last_frame_number;
+            allocator.next_frame = Frame::from_number(cursor);
+            loop {
+                let expected = first_free_frame_per_bit(&used, cursor);
+                assert_eq!(allocator.allocate_frame().map(|frame| frame.number()), expected);
+                match expected {
+                    Some(frame_number) => {
+                        used[frame_number] = true;
+                        cursor = frame_number + 1;
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotating_allocator_does_not_reuse_freed_frame_right_away() {
+        let mut bitmap = vec![0; 1];
+        let mut rotating = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        rotating.set_rotate(true);
+
+        let frame = rotating.allocate_frame().unwrap();
+        assert_eq!(frame.number(), 1);
+        rotating.deallocate_frame(frame);
+        let frames = (0..14).map(|_| rotating.allocate_frame().unwrap().number()).collect::<Vec<_>>();
+        assert_eq!(frames, (2..16).collect::<Vec<_>>());
+        // only frame 1 is left, so the cursor wraps around to it
+        assert_eq!(rotating.allocate_frame().unwrap().number(), 1);
+        assert_eq!(rotating.allocate_frame(), None);
+
+        let mut bitmap = vec![0; 1];
+        let mut lowest_first = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        let frame = lowest_first.allocate_frame().unwrap();
+        lowest_first.deallocate_frame(frame);
+        assert_eq!(lowest_first.allocate_frame(), Some(frame));
+    }
+
+    #[test]
+    fn colored_frames_have_their_color() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(0, 4 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        allocator.allocate_frame_at(Frame::from_number(5)).unwrap();
+
+        for _ in 0..20 {
+            let frame = allocator.allocate_frame_with_color(1, 4).unwrap();
+            assert_eq!(frame.color(4), 1);
+            assert_ne!(frame.number(), 5);
+        }
+        let frame = allocator.allocate_frame_with_color(6, 7).unwrap();
+        assert_eq!(frame.number() % 7, 6);
+    }
+
+    #[test]
+    fn exhausted_color_leaves_other_colors_alone() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(0, 4 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        // a fully used block is stepped over
+        for frame_number in BITS_PER_BLOCK..2 * BITS_PER_BLOCK {
+            allocator.set_used(frame_number, true);
+        }
+        let free_of_color = |allocator: &BitmapFrameAllocator, color| {
+            allocator.free_frames().filter(|frame| frame.color(4) == color).count()
+        };
+        let free_of_other_colors = [0, 1, 3].iter().map(|&color| free_of_color(&allocator, color)).collect::<Vec<_>>();
+
+        let mut allocated = 0;
+        while let Some(frame) = allocator.allocate_frame_with_color(2, 4) {
+            assert_eq!(frame.color(4), 2);
+            allocated += 1;
+        }
+        assert_eq!(allocated, 3 * BITS_PER_BLOCK / 4);
+        assert_eq!(free_of_color(&allocator, 2), 0);
+        assert_eq!([0, 1, 3].iter().map(|&color| free_of_color(&allocator, color)).collect::<Vec<_>>(),
+                   free_of_other_colors);
+
+        // a freed frame below the cursor is found again
+        allocator.deallocate_frame(Frame::from_number(10));
+        assert_eq!(allocator.allocate_frame_with_color(2, 4).unwrap().number(), 10);
+        assert_eq!(allocator.allocate_frame_with_color(3, 4).unwrap().number(), 3);
+    }
+
+    #[test]
+    fn heap_region_is_contiguous_and_used() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        allocator.set_used(10, true);
+
+        let heap = allocator.reserve_heap(64).unwrap();
+        assert_eq!(heap.len(), 64);
+        assert_eq!(heap.start.number(), 11);
+        assert!(heap.clone().all(|frame| allocator.frame_is_used(frame.number())));
+        assert!(!allocator.frame_is_used(heap.end.number()));
+
+        let free_frames = allocator.free_frame_count();
+        assert_eq!(allocator.reserve_heap(allocator.largest_free_run() + 1), None);
+        assert_eq!(allocator.free_frame_count(), free_frames);
+    }
+
+    #[test]
+    fn contiguous_aligned_run_is_aligned() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        allocator.set_used(17, true);
+
+        let frame = allocator.allocate_contiguous_aligned(3, 16).unwrap();
+        assert_eq!(frame.number(), 32);
+        for frame_number in 32..35 {
+            assert!(allocator.frame_is_used(frame_number));
+        }
+        assert!(!allocator.frame_is_used(16));
+    }
+
+    #[test]
+    fn contiguous_aligned_run_wider_than_block() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x200000)]);
+        let align = 2 * BITS_PER_BLOCK;
+
+        let frame = allocator.allocate_contiguous_aligned(4, align).unwrap();
+        assert_eq!(frame.number(), align);
+        assert!((align..align + 4).all(|n| allocator.frame_is_used(n)));
+
+        let frame = allocator.allocate_contiguous_aligned(4, align).unwrap();
+        assert_eq!(frame.number(), 2 * align);
+    }
+
+    #[test]
+    fn contiguous_aligned_by_one_matches_plain_path() {
+        let mut bitmap1 = vec![0; 32];
+        let mut bitmap2 = vec![0; 32];
+        let mut allocator1 = allocator(&mut bitmap1, &[(0, 0x100000)]);
+        let mut allocator2 = allocator(&mut bitmap2, &[(0, 0x100000)]);
+        for &frame_number in &[3, 5, 6, 63, 64, 70] {
+            allocator1.set_used(frame_number, true);
+            allocator2.set_used(frame_number, true);
+        }
+
+        for &count in &[1, 2, 5, 3] {
+            assert_eq!(allocator1.allocate_contiguous_aligned(count, 1).unwrap().number(),
+                       allocator2.allocate_contiguous_frames(count).unwrap().number());
+        }
+    }
+
+    #[test]
+    fn huge_frame_is_aligned() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x600000)]);
+        let free_before = allocator.free_frame_count();
+
+        // frame 0 is used, so the first huge frame starts at 2 MiB
+        let frame = allocator.allocate_huge_frame().unwrap();
+        assert_eq!(frame.start_address(), 0x200000);
+        assert!((512..1024).all(|n| allocator.frame_is_used(n)));
+        assert!(!allocator.frame_is_used(511) && !allocator.frame_is_used(1024));
+        assert_eq!(allocator.free_frame_count(), free_before - 512);
+
+        allocator.deallocate_huge_frame(frame);
+        assert!((512..1024).all(|n| !allocator.frame_is_used(n)));
+        assert_eq!(allocator.free_frame_count(), free_before);
+    }
+
+    #[test]
+    fn reserving_huge_frames_reserves_their_regular_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x600000)]);
+        allocator.allocate_frame_at(Frame::from_number(600)).unwrap();
+
+        assert_eq!(allocator.reserve_huge_frames(HugeFrame::from_number(1), HugeFrame::from_number(2)), 511);
+        assert!((512..1024).all(|n| allocator.frame_is_used(n) && allocator.frame_is_reserved(n)));
+        assert!(!allocator.frame_is_used(1024));
+        assert_eq!(allocator.allocate_huge_frame().unwrap().start_address(), 0x400000);
+    }
+
+    #[test]
+    fn huge_frame_skips_misaligned_free_run() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x600000)]);
+        for frame_number in (0..1024).filter(|&n| n < 600 || n >= 600 + 512) {
+            allocator.set_used(frame_number, true);
+        }
+
+        assert_eq!(allocator.allocate_huge_frame().unwrap().number(), 1024);
+        assert!(allocator.allocate_huge_frame().is_none());
+    }
+
+    #[test]
+    fn huge_frame_can_be_freed_page_by_page() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x400000)]);
+        let free_before = allocator.free_frame_count();
+
+        let frame = allocator.allocate_huge_frame().unwrap();
+        for frame_number in frame.number()..frame.number() + 512 {
+            allocator.deallocate_frame(Frame::from_number(frame_number));
+        }
+        assert_eq!(allocator.free_frame_count(), free_before);
+        assert_eq!(allocator.allocate_huge_frame().unwrap().number(), frame.number());
+    }
+
+    #[test]
+    fn allocate_frame_below_limit() {
+        let mut bitmap = vec![0; 256];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x2000000)]);
+        let limit = 0x1000000;
+        for frame_number in 0..limit / PAGE_SIZE {
+            allocator.set_used(frame_number, true);
+        }
+        allocator.set_used(0x800, false);
+
+        let frame = allocator.allocate_frame_below(limit).unwrap();
+        assert_eq!(frame.number(), 0x800);
+        assert_eq!(allocator.allocate_frame_below(limit), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.next_frame.number(), 0);
+        assert!(allocator.allocate_frame().unwrap().start_address() >= limit);
+    }
+
+    #[test]
+    fn allocate_frame_in_range() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        for frame_number in 60..70 {
+            allocator.set_used(frame_number, true);
+        }
+        allocator.set_used(72, true);
+
+        // entirely used, crossing a block boundary
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(60), Frame::from_number(70)),
+                   Err(FrameAllocError::OutOfMemory));
+        // partially used, starting mid-block
+        let frame = allocator.allocate_frame_in_range(Frame::from_number(65), Frame::from_number(80)).unwrap();
+        assert_eq!(frame.number(), 70);
+        // smaller than one block
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(71), Frame::from_number(74)).unwrap().number(), 71);
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(71), Frame::from_number(74)).unwrap().number(), 73);
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(71), Frame::from_number(74)),
+                   Err(FrameAllocError::OutOfMemory));
+
+        assert_eq!(allocator.next_frame.number(), 0);
+        assert!(!allocator.second_scan);
+    }
+
+    #[test]
+    fn allocate_frame_in_range_below_isa_dma_limit() {
+        let mut bitmap = vec![0; 256];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x2000000)]);
+        // the general purpose allocator has already used up low memory
+        while allocator.next_frame.start_address() < 0x1000000 - PAGE_SIZE {
+            allocator.allocate_frame().unwrap();
+        }
+
+        let frame = allocator.allocate_frame_in_range(Frame::from_number(0), Frame::containing_address(PhysicalAddress::new(0x1000000))).unwrap();
+        assert!(frame.start_address() < 0x1000000);
+        assert!(allocator.frame_is_used(frame.number()));
+    }
+
+    #[test]
+    fn inclusive_range_includes_its_last_frame() {
+        let mut bitmap = vec![0; 256];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x2000000)]);
+        while allocator.next_frame.start_address() < 0x1000000 - PAGE_SIZE {
+            allocator.allocate_frame().unwrap();
+        }
+
+        let last_isa_frame = Frame::containing_address(PhysicalAddress::new(0xffffff)).number();
+        let frame = allocator.allocate_frame_in_range_inclusive(Frame::from_number(0), Frame::from_number(last_isa_frame)).unwrap();
+        assert_eq!(frame.number(), last_isa_frame);
+        assert_eq!(allocator.allocate_frame_in_range_inclusive(Frame::from_number(0), Frame::from_number(last_isa_frame)), None);
+        assert_eq!(allocator.allocate_frame_in_range_inclusive(Frame::from_number(last_isa_frame), Frame::from_number(0)), None);
+    }
+
+    #[test]
+    fn inclusive_range_ending_at_the_highest_frame_number_is_refused() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        let max = Frame::from_number(core::usize::MAX);
+        assert_eq!(allocator.allocate_frame_in_range_inclusive(Frame::from_number(1), max), None);
+    }
+
+    #[test]
+    fn allocation_hints_order_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let last_frame_number = allocator.last_frame.number();
+
+        let low1 = allocator.allocate_frame_with_hint(AllocHint::PreferLow).unwrap().number();
+        let high1 = allocator.allocate_frame_with_hint(AllocHint::PreferHigh).unwrap().number();
+        let low2 = allocator.allocate_frame_with_hint(AllocHint::PreferLow).unwrap().number();
+        let high2 = allocator.allocate_frame_with_hint(AllocHint::PreferHigh).unwrap().number();
+        assert_eq!((low1, low2), (1, 2));
+        assert_eq!((high1, high2), (last_frame_number - 1, last_frame_number - 2));
+
+        // the next-fit cursor is independent of both
+        assert_eq!(allocator.allocate_frame_with_hint(AllocHint::Any).unwrap().number(), 3);
+        let below = allocator.allocate_frame_with_hint(AllocHint::Below(0x10000)).unwrap().number();
+        assert!(below < 0x10);
+
+        // freed frames are found again from either end
+        allocator.deallocate_frame(Frame::from_number(low1));
+        allocator.deallocate_frame(Frame::from_number(high1));
+        assert_eq!(allocator.allocate_frame_with_hint(AllocHint::PreferLow).unwrap().number(), low1);
+        assert_eq!(allocator.allocate_frame_with_hint(AllocHint::PreferHigh).unwrap().number(), high1);
+    }
+
+    #[test]
+    fn below_hint_does_not_fall_back() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        for frame_number in 0..0x10 {
+            allocator.set_used(frame_number, true);
+        }
+
+        assert!(allocator.allocate_frame_with_hint(AllocHint::Below(0x10000)).is_none());
+        assert!(allocator.allocate_frame_with_hint(AllocHint::PreferLow).unwrap().number() >= 0x10);
+    }
+
+    #[test]
+    fn prefer_high_exhausts_memory() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free = allocator.free_frame_count();
+
+        let frames: Vec<usize> = (0..free).map(|_| allocator.allocate_frame_with_hint(AllocHint::PreferHigh)
+                                                            .unwrap().number()).collect();
+        assert!(frames.windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(allocator.allocate_frame_with_hint(AllocHint::PreferHigh).is_none());
+    }
+
+    #[test]
+    fn deallocate_range_clears_whole_blocks() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x600000)]);
+
+        let range = allocator.allocate_frames(1024).unwrap();
+        assert!((1..1025).all(|n| allocator.frame_is_used(n)));
+        allocator.deallocate_range(range);
+        assert!((1..1025).all(|n| !allocator.frame_is_used(n)));
+        assert!((1..1024 / BITS_PER_BLOCK).all(|block| allocator.bitmap[block] == 0));
+        assert!(allocator.frame_is_used(0));
+    }
+
+    #[test]
+    fn deallocate_range_within_single_block() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let _ = allocator.allocate_frames(10).unwrap();
+
+        allocator.deallocate_range(Frame::range_inclusive(Frame::from_number(3), Frame::from_number(6)));
+        let free: Vec<usize> = (0..12).filter(|&n| !allocator.frame_is_used(n)).collect();
+        assert_eq!(free, vec![3, 4, 5, 6, 11]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range contains frames which are not allocated")]
+    fn deallocate_range_detects_double_free() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let _ = allocator.allocate_frames(4).unwrap();
+
+        allocator.deallocate_range(Frame::range_inclusive(Frame::from_number(1), Frame::from_number(5)));
+    }
+
+    #[test]
+    fn deallocate_frames_frees_allocated_run() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+
+        let range = allocator.allocate_frames(5).unwrap();
+        assert_eq!(allocator.deallocate_frames(range), Ok(()));
+        assert!((1..6).all(|n| !allocator.frame_is_used(n)));
+        assert_eq!(allocator.free_frame_count(), free_before);
+    }
+
+    #[test]
+    fn deallocate_frames_reports_frame_which_is_not_allocated() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let _ = allocator.allocate_frames(5).unwrap();
+        allocator.deallocate_frame(Frame::from_number(3));
+
+        let range = Frame::range_inclusive(Frame::from_number(1), Frame::from_number(5));
+        assert_eq!(allocator.deallocate_frames(range), Err(Frame::from_number(3)));
+        assert!([1, 2, 4, 5].iter().all(|&n| allocator.frame_is_used(n)));
+    }
+
+    #[test]
+    fn usable_as_trait_object() {
+        fn allocate_two<A: FrameAllocator>(mut allocator: A) -> (Frame, Frame) {
+            (allocator.allocate_frame().unwrap(), allocator.allocate_frame().unwrap())
+        }
+
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let free_before = allocator.free_frame_count();
+        {
+            let dyn_allocator: &mut dyn FrameManager = &mut allocator;
+            let frame = dyn_allocator.allocate_frame().unwrap();
+            assert_eq!(frame.number(), 1);
+            dyn_allocator.deallocate_frame(frame);
+
+            let (frame1, frame2) = allocate_two(&mut *dyn_allocator);
+            assert_eq!((frame1.number(), frame2.number()), (1, 2));
+            dyn_allocator.deallocate_frame(frame1);
+            dyn_allocator.deallocate_frame(frame2);
+        }
+        assert_eq!(allocator.free_frame_count(), free_before);
+    }
+
+    #[test]
+    fn allocate_iter_allocates_lazily() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+
+        let frames: Vec<usize> = {
+            let mut iter = allocator.allocate_iter(3);
+            let first = iter.next().unwrap().number();
+            assert!(!iter.allocator.frame_is_used(2));
+            let mut frames = vec![first];
+            frames.extend(iter.by_ref().map(|frame| frame.number()));
+            assert!(!iter.exhausted());
+            frames
+        };
+        assert_eq!(frames, vec![1, 2, 3]);
+        assert!(frames.iter().all(|&n| allocator.frame_is_used(n)));
+        assert!(!allocator.frame_is_used(4));
+    }
+
+    #[test]
+    fn allocate_iter_stops_when_memory_runs_out() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x40000)]);
+        for _ in 0..60 {
+            allocator.allocate_frame().unwrap();
+        }
+
+        let mut iter = allocator.allocate_iter(10);
+        assert_eq!(iter.by_ref().count(), 3);
+        assert!(iter.exhausted());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn allocate_iter_rollback_frees_yielded_frames() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        allocator.allocate_frame().unwrap();
+        let free_before = allocator.free_frame_count();
+
+        {
+            let mut iter = allocator.allocate_iter(8);
+            for _ in iter.by_ref().take(5) {}
+            iter.rollback();
+        }
+        assert_eq!(allocator.free_frame_count(), free_before);
+        assert!((2..7).all(|n| !allocator.frame_is_used(n)));
+    }
+
+    fn fragment(allocator: &mut BitmapFrameAllocator) {
+        for frame_number in (0..allocator.last_frame.number()).filter(|n| n % 3 == 0 || n % 7 == 0) {
+            allocator.set_used(frame_number, true);
+        }
+    }
+
+    #[test]
+    fn allocate_frames_into_matches_single_allocations() {
+        let mut bitmap1 = vec![0; 32];
+        let mut bitmap2 = vec![0; 32];
+        let mut allocator1 = allocator(&mut bitmap1, &[(0, 0x100000)]);
+        let mut allocator2 = allocator(&mut bitmap2, &[(0, 0x100000)]);
+        fragment(&mut allocator1);
+        fragment(&mut allocator2);
+        allocator1.next_frame = Frame::from_number(200);
+        allocator2.next_frame = Frame::from_number(200);
+
+        let mut frames: Vec<Frame> = (0..100).map(|_| Frame::from_number(0)).collect();
+        assert_eq!(allocator1.allocate_frames_into(&mut frames), Ok(100));
+        for frame in &frames {
+            assert_eq!(frame.number(), allocator2.allocate_frame().unwrap().number());
+            assert!(allocator1.frame_is_used(frame.number()));
+        }
+        assert_eq!(allocator1.allocate_frame().unwrap().number(), allocator2.allocate_frame().unwrap().number());
+    }
+
+    #[test]
+    fn allocate_frames_into_rolls_back_on_failure() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        fragment(&mut allocator);
+        let used_before: Vec<bool> = (0..256).map(|n| allocator.frame_is_used(n)).collect();
+        let free = used_before.iter().filter(|&&used| !used).count();
+
+        let mut frames: Vec<Frame> = (0..free + 1).map(|_| Frame::from_number(0)).collect();
+        assert_eq!(allocator.allocate_frames_into(&mut frames), Err(FrameAllocError::OutOfMemory));
+        let used_after: Vec<bool> = (0..256).map(|n| allocator.frame_is_used(n)).collect();
+        assert_eq!(used_before, used_after);
+
+        frames.pop();
+        assert_eq!(allocator.allocate_frames_into(&mut frames), Ok(free));
+    }
+
+    #[test]
+    fn allocate_frames_returns_inclusive_range() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+
+        let frames: Vec<usize> = allocator.allocate_frames(5).unwrap().map(|f| f.number()).collect();
+        assert_eq!(frames, vec![1, 2, 3, 4, 5]);
+        assert!(frames.iter().all(|&n| allocator.frame_is_used(n)));
+    }
+
+    #[test]
+    fn allocate_frames_rejects_run_past_last_frame() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+        let last_frame_number = allocator.last_frame.number();
+        for frame_number in 0..last_frame_number - 3 {
+            allocator.set_used(frame_number, true);
+        }
+
+        assert!(allocator.allocate_frames(4).is_err());
+        for frame_number in last_frame_number - 3..last_frame_number {
+            assert!(!allocator.frame_is_used(frame_number));
+        }
+        assert_eq!(allocator.allocate_frames(3).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn allocate_single_frame_matches_allocate_frame() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+
+        let frame = allocator.allocate_frame().unwrap();
+        let mut range = allocator.allocate_frames(1).unwrap();
+        assert_eq!(range.next().unwrap().number(), frame.number() + 1);
+        assert!(range.next().is_none());
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x100000)]);
+
+        assert!(allocator.allocate_frames(0).is_err());
+        assert_eq!(allocator.allocate_contiguous_frames(0), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.allocate_contiguous_aligned(4, 0), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(10), Frame::from_number(10)),
+                   Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(20), Frame::from_number(10)),
+                   Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.used_frame_count(), 1);
+    }
+
+    #[test]
+    fn reserve_is_kept_for_critical_allocations() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 8 * PAGE_SIZE)]);
+        allocator.set_reserve(2);
+
+        let frames: Vec<_> = (0..5).map(|_| allocator.allocate_frame().unwrap()).collect();
+        assert!(allocator.allocate_frame().is_none());
+        assert_eq!(allocator.allocate_contiguous_frames(1), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.free_frame_count(), 2);
+
+        assert!(allocator.allocate_frame_critical().is_some());
+        assert!(allocator.allocate_frame_critical().is_some());
+        assert!(allocator.allocate_frame_critical().is_none());
+
+        for frame in frames.into_iter().take(3) {
+            allocator.deallocate_frame(frame);
+        }
+        assert!(allocator.allocate_frame().is_some());
+        assert!(allocator.allocate_frame().is_none());
+    }
+
+    /// An allocator over 1024 frames whose free frames are all kept back by `set_reserve`
+    fn only_reserved_left<'a>(bitmap: &'a mut [usize]) -> BitmapFrameAllocator<'a> {
+        let mut allocator = allocator(bitmap, &[(0, 1024 * PAGE_SIZE)]);
+        let free_frames = allocator.free_frame_count();
+        allocator.set_reserve(free_frames);
+        allocator
+    }
+
+    fn assert_nothing_allocated(allocator: &BitmapFrameAllocator, requested: usize) {
+        assert_eq!(allocator.used_frame_count(), 1);
+        let failure = allocator.last_failure().unwrap();
+        assert_eq!(failure.kind, FrameAllocError::OutOfMemory);
+        assert_eq!(failure.requested, requested);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frames_aligned() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_frames_aligned(4, 4).err(), Some(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 4);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frames_best_fit() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_frames_best_fit(2).err(), Some(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 2);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frame_in_range() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(1), Frame::from_number(10)),
+                   Err(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 1);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frame_dma32() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_frame_dma32(), None);
+        assert_nothing_allocated(&allocator, 1);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_contiguous_dma32() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_contiguous_dma32(3), Err(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 3);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_huge_frame() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_huge_frame(), None);
+        assert_nothing_allocated(&allocator, FRAMES_PER_HUGE_FRAME);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frame_at() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        assert_eq!(allocator.allocate_frame_at(Frame::from_number(8)), Err(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 1);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frames_into() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        let mut frames = [Frame::from_number(0); 4];
+        assert_eq!(allocator.allocate_frames_into(&mut frames), Err(FrameAllocError::OutOfMemory));
+        assert_nothing_allocated(&allocator, 4);
+    }
+
+    #[test]
+    fn reserve_is_kept_from_allocate_frame_with_hint() {
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        for &hint in &[AllocHint::PreferLow, AllocHint::PreferHigh, AllocHint::Below(0x100000)] {
+            assert_eq!(allocator.allocate_frame_with_hint(hint), None);
+            assert_nothing_allocated(&allocator, 1);
+        }
+    }
+
+    #[test]
+    fn out_of_memory_handler_is_asked_by_every_allocation_method() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn handler(_allocator: &mut BitmapFrameAllocator, _info: &OomInfo) -> OomAction {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            OomAction::Fail
+        }
+
+        let mut bitmap = vec![0; 16];
+        let mut allocator = only_reserved_left(&mut bitmap);
+        allocator.set_oom_handler(handler);
+        allocator.allocate_frame_at(Frame::from_number(8)).unwrap_err();
+        allocator.allocate_contiguous_dma32(2).unwrap_err();
+        allocator.allocate_huge_frame();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn zero_on_alloc_applies_to_every_allocation_method() {
+        static ZEROED: AtomicUsize = AtomicUsize::new(0);
+        fn zeroer(_frame: &Frame) {
+            ZEROED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut bitmap = vec![0; 16];
+        let mut allocator = allocator(&mut bitmap, &[(0, 1024 * PAGE_SIZE)]);
+        unsafe { allocator.set_frame_zeroer(zeroer); }
+        allocator.set_zero_on_alloc(true);
+        allocator.allocate_frame_at(Frame::from_number(8)).unwrap();
+        allocator.allocate_contiguous_dma32(3).unwrap();
+        allocator.allocate_frames_best_fit(2).unwrap();
+        assert_eq!(ZEROED.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn full_last_block_wraps_around() {
+        let mut bitmap = vec![0; 2];
+        let mut allocator = allocator(&mut bitmap, &[(0, 2 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        let last_frame_number = allocator.last_frame.number();
+        for frame_number in BITS_PER_BLOCK..last_frame_number {
+            allocator.allocate_frame_at(Frame::from_number(frame_number)).unwrap();
+        }
+
+        allocator.next_frame = Frame::from_number(BITS_PER_BLOCK);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 1);
+        assert_eq!(allocator.next_frame.number(), 2);
+    }
+
+    static STASHED_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+    fn free_stashed_frame(allocator: &mut BitmapFrameAllocator, info: &OomInfo) -> OomAction {
+        assert_eq!(info.free_frames, 0);
+        match STASHED_FRAME.swap(0, Ordering::SeqCst) {
+            0 => OomAction::Fail,
+            frame_number => {
+                allocator.deallocate_frame(Frame::from_number(frame_number));
+                OomAction::Retry
+            },
+        }
+    }
+
+    #[test]
+    fn oom_handler_can_free_memory() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 8 * PAGE_SIZE)]);
+        allocator.set_oom_handler(free_stashed_frame);
+        while allocator.allocate_frame().is_some() {}
+
+        STASHED_FRAME.store(3, Ordering::SeqCst);
+        assert_eq!(allocator.allocate_frame_with_hint(AllocHint::PreferHigh).unwrap().number(), 3);
+        STASHED_FRAME.store(5, Ordering::SeqCst);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 5);
+        assert!(allocator.allocate_frame().is_none());
+        assert_eq!(allocator.allocate_contiguous_frames(1), Err(FrameAllocError::OutOfMemory));
+    }
+
+    #[test]
+    fn kernel_frames_cannot_be_freed() {
+        let mut bitmap = vec![0; 32];
+        let reserved = reserved_bitmap(bitmap.len());
+        let mut allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved,
+                                                             PhysicalAddress::new(0x100000), PhysicalAddress::new(0x101fff),
+                                                             PhysicalAddress::new(0x102000), PhysicalAddress::new(0x102fff),
+                                                             vec![(0, 0x200000)].into_iter()).unwrap();
+
+        assert_eq!(allocator.try_deallocate_frame(Frame::from_number(0x101)), Err(FrameAllocError::Reserved));
+        assert_eq!(allocator.try_deallocate_frame(Frame::from_number(0x102)), Err(FrameAllocError::Reserved));
+        assert!(allocator.frame_is_used(0x101) && allocator.frame_is_used(0x102));
+        assert!(allocator.free_frames().all(|frame| frame.number() < 0x100 || frame.number() > 0x102));
+    }
+
+    #[test]
+    fn dma32_allocations_stay_below_4_gib() {
+        let mut bitmap = vec![0; 2 * ARRAY_SIZE];
+        let high_memory = 0x100000000;
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x10000), (high_memory, high_memory + 0x100000)]);
+
+        let frames: Vec<_> = (0..15).map(|_| allocator.allocate_frame_dma32().unwrap()).collect();
+        assert!(frames.iter().all(|frame| frame.start_address() < high_memory));
+        assert!(allocator.allocate_frame_dma32().is_none());
+        assert_eq!(allocator.allocate_contiguous_dma32(1), Err(FrameAllocError::OutOfMemory));
+        assert!(allocator.allocate_frame().unwrap().start_address() >= high_memory);
+
+        for frame in frames.into_iter().skip(2).take(3) {
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(allocator.dma32_frame.number(), 3);
+        assert_eq!(allocator.allocate_contiguous_dma32(4), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.allocate_contiguous_dma32(3), Ok(Frame::from_number(3)));
+        assert_eq!(allocator.dma32_frame.number(), 6);
+    }
+
+    #[test]
+    fn contiguous_dma32_allocations_advance_the_cursor() {
+        let mut bitmap = vec![0; 2 * ARRAY_SIZE];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x20000)]);
+
+        assert_eq!(allocator.allocate_contiguous_dma32(4), Ok(Frame::from_number(1)));
+        assert_eq!(allocator.dma32_frame.number(), 5);
+        assert_eq!(allocator.allocate_contiguous_dma32(2), Ok(Frame::from_number(5)));
+        assert_eq!(allocator.dma32_frame.number(), 7);
+
+        // a free frame below the run keeps the cursor where it is
+        allocator.deallocate_frame(Frame::from_number(2));
+        assert_eq!(allocator.allocate_contiguous_dma32(2), Ok(Frame::from_number(7)));
+        assert_eq!(allocator.dma32_frame.number(), 2);
+        assert_eq!(allocator.allocate_frame_dma32(), Some(Frame::from_number(2)));
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x30000), (0x40000, 0xc0000)]);
+        for _ in 0..10 {
+            allocator.allocate_frame().unwrap();
+        }
+        let mut snapshot = vec![0; 4];
+        assert_eq!(allocator.snapshot(&mut snapshot[..1]), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.snapshot(&mut snapshot), Ok(4));
+        let free_before: Vec<_> = allocator.free_frames().map(|frame| frame.number()).collect();
+        let free_count = allocator.free_frame_count();
+
+        while allocator.allocate_frame().is_some() {}
+        assert_eq!(allocator.restore(&snapshot[..3]), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.restore(&[0; 4]), Err(FrameAllocError::Reserved));
+        assert_eq!(allocator.restore(&snapshot), Ok(()));
+        assert_eq!(allocator.free_frames().map(|frame| frame.number()).collect::<Vec<_>>(), free_before);
+        assert_eq!(allocator.free_frame_count(), free_count);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), free_before[0]);
+    }
+
+    /// Serializes the allocator, deserializes it again and checks that nothing was lost
+    fn assert_serialize_round_trip(allocator: &BitmapFrameAllocator) -> usize {
+        let mut bytes = vec![0; 4096];
+        let written = allocator.serialize_into(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], &SERIALIZE_MAGIC);
+
+        let mut bitmap = vec![0; allocator.bitmap.len()];
+        let mut reserved = vec![0; allocator.bitmap.len()];
+        let copy = BitmapFrameAllocator::deserialize_from(&mut bitmap, &mut reserved, &bytes[..written]).unwrap();
+        let len = allocator.snapshot_len();
+        assert_eq!(&copy.bitmap[..len], &allocator.bitmap[..len]);
+        assert_eq!(&copy.reserved[..len], &allocator.reserved[..len]);
+        assert_eq!((copy.base_frame, copy.last_frame), (allocator.base_frame, allocator.last_frame));
+        assert_eq!((copy.free_frame_count(), copy.used_frame_count()),
+                   (allocator.free_frame_count(), allocator.used_frame_count()));
+        written
+    }
+
+    #[test]
+    fn serialized_state_round_trips() {
+        let mut bitmap = vec![0; 64];
+        let mut allocator = allocator(&mut bitmap, &[(0x10000, 0x9f000), (0x100000, 0x1000000)]);
+        // mostly homogeneous, so far smaller than the raw bitmap
+        assert!(assert_serialize_round_trip(&allocator) < 64 * WORD_BYTES);
+
+        allocator.reserve_region(PhysicalAddress::new(0x200000), PhysicalAddress::new(0x2fffff));
+        for _ in 0..1000 {
+            allocator.allocate_frame().unwrap();
+        }
+        for frame_number in (0x400..0x500).filter(|n| n % 3 == 0) {
+            allocator.deallocate_frame(Frame::from_number(frame_number));
+        }
+        assert_serialize_round_trip(&allocator);
+
+        while allocator.allocate_frame().is_some() {}
+        assert_serialize_round_trip(&allocator);
+    }
+
+    #[test]
+    fn serialized_free_bitmap_round_trips() {
+        let mut bitmap = vec![0; 4];
+        let allocator = allocator(&mut bitmap, &[(0, 4 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        assert_eq!(allocator.used_frame_count(), 1);
+        assert_serialize_round_trip(&allocator);
+
+        let mut bitmap = vec![0; 2];
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        let allocator = BitmapFrameAllocator::from_areas_with_base(&mut bitmap, reserved, Frame::from_number(256),
+                                                                   zero, zero, zero, zero,
+                                                                   vec![(0, 0x200000)].into_iter()).unwrap();
+        assert_eq!(allocator.used_frame_count(), 0);
+        assert_serialize_round_trip(&allocator);
+    }
+
+    #[test]
+    fn bad_serialized_state_is_rejected() {
+        let mut bitmap = vec![0; 4];
+        let allocator = allocator(&mut bitmap, &[(0, 0x80000)]);
+        let mut bytes = vec![0; 256];
+        assert_eq!(allocator.serialize_into(&mut bytes[..20]), Err(SerializeError::BufferTooSmall));
+        let written = allocator.serialize_into(&mut bytes).unwrap();
+
+        let mut storage = vec![0; 4];
+        let mut reserved = vec![0; 4];
+        let mut deserialize = |bytes: &[u8]| {
+            BitmapFrameAllocator::deserialize_from(&mut storage, &mut reserved, bytes).err()
+        };
+        assert_eq!(deserialize(&bytes[..written - 1]), Some(SerializeError::Truncated));
+        let mut corrupt = bytes.clone();
+        corrupt[0] = b'X';
+        assert_eq!(deserialize(&corrupt), Some(SerializeError::BadMagic));
+        let mut corrupt = bytes.clone();
+        corrupt[4] = 2;
+        assert_eq!(deserialize(&corrupt), Some(SerializeError::UnsupportedVersion(2)));
+        let mut corrupt = bytes.clone();
+        corrupt[5] = 4;
+        assert_eq!(deserialize(&corrupt), Some(SerializeError::WordSizeMismatch(4)));
+        let mut corrupt = bytes.clone();
+        corrupt[6 + 2 * WORD_BYTES] += 1;
+        assert_eq!(deserialize(&corrupt), Some(SerializeError::Corrupt));
+
+        let mut small = vec![0; 1];
+        let mut small_reserved = vec![0; 1];
+        let result = BitmapFrameAllocator::deserialize_from(&mut small, &mut small_reserved, &bytes);
+        assert_eq!(result.err(), Some(SerializeError::BufferTooSmall));
+    }
+
+    #[test]
+    fn best_fit_picks_smallest_run() {
+        let mut bitmap = vec![0; 1];
+        // free runs of 2, 4, 8 and 4 frames
+        let mut allocator = allocator(&mut bitmap, &[(2 * PAGE_SIZE, 4 * PAGE_SIZE), (5 * PAGE_SIZE, 9 * PAGE_SIZE),
+                                                     (10 * PAGE_SIZE, 18 * PAGE_SIZE), (20 * PAGE_SIZE, 24 * PAGE_SIZE)]);
+
+        let mut range = allocator.allocate_frames_best_fit(3).unwrap();
+        assert_eq!(range.next().unwrap().number(), 5);
+        assert_eq!(range.len(), 2);
+        assert_eq!(allocator.allocate_frames_best_fit(3).unwrap().next().unwrap().number(), 20);
+        assert_eq!(allocator.allocate_frames_best_fit(2).unwrap().next().unwrap().number(), 2);
+        assert_eq!(allocator.allocate_frames_best_fit(5).unwrap().next().unwrap().number(), 10);
+        assert_eq!(allocator.allocate_frames_best_fit(4).err(), Some(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.allocate_frames_best_fit(0).err(), Some(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    fn first_fit_reuses_lowest_frame() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 32 * PAGE_SIZE)]);
+        allocator.set_alloc_policy(AllocPolicy::FirstFit);
+        for _ in 0..8 {
+            allocator.allocate_frame().unwrap();
+        }
+
+        allocator.deallocate_frame(Frame::from_number(6));
+        allocator.deallocate_frame(Frame::from_number(3));
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 3);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 6);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 9);
+    }
+
+    /// 256 blocks of which each has its first frame used, so every free run is 63 frames long,
+    /// except for the last block that is free completely
+    fn fragmented_scan_cost<F>(policy: AllocPolicy, mut allocate: F) -> usize
+        where F: FnMut(&mut BitmapFrameAllocator)
+    {
+        let mut bitmap = vec![0; 256];
+        let mut allocator = allocator(&mut bitmap, &[(0, 256 * BITS_PER_BLOCK * PAGE_SIZE)]);
+        for block_number in 0..255 {
+            allocator.claim_frame(allocator.first_frame_in_block(block_number));
+        }
+        allocator.set_alloc_policy(policy);
+        allocator.scanned_blocks.set(0);
+        allocate(&mut allocator);
+        allocator.scanned_blocks.get()
+    }
+
+    #[test]
+    fn single_frame_scan_cost_does_not_depend_on_policy() {
+        let allocate_frames = |allocator: &mut BitmapFrameAllocator| for _ in 0..1000 {
+            allocator.allocate_frame().unwrap();
+        };
+        // first-fit resumes at its own cursor instead of scanning from frame 0 every time
+        let next_fit = fragmented_scan_cost(AllocPolicy::NextFit, allocate_frames);
+        assert_eq!(fragmented_scan_cost(AllocPolicy::FirstFit, allocate_frames), next_fit);
+        assert_eq!(fragmented_scan_cost(AllocPolicy::BestFit, allocate_frames), next_fit);
+        assert!(next_fit <= 1000 + 256);
+    }
+
+    #[test]
+    fn best_fit_scans_all_of_memory_unless_a_run_fits_exactly() {
+        let small_run = |allocator: &mut BitmapFrameAllocator| { allocator.allocate_contiguous_frames(8).unwrap(); };
+        assert!(fragmented_scan_cost(AllocPolicy::NextFit, small_run) <= 2);
+        assert!(fragmented_scan_cost(AllocPolicy::FirstFit, small_run) <= 2);
+        assert!(fragmented_scan_cost(AllocPolicy::BestFit, small_run) >= 256);
+
+        let exact_run = |allocator: &mut BitmapFrameAllocator| { allocator.allocate_contiguous_frames(63).unwrap(); };
+        assert!(fragmented_scan_cost(AllocPolicy::BestFit, exact_run) <= 2);
+
+        // only the last block fits, every policy has to get there
+        let large_run = |allocator: &mut BitmapFrameAllocator| { allocator.allocate_contiguous_frames(64).unwrap(); };
+        assert!(fragmented_scan_cost(AllocPolicy::NextFit, large_run) >= 256);
+        assert!(fragmented_scan_cost(AllocPolicy::BestFit, large_run) >= 256);
+    }
+
+    #[test]
+    fn best_fit_preserves_large_runs() {
+        let areas = [(2 * PAGE_SIZE, 10 * PAGE_SIZE), (12 * PAGE_SIZE, 14 * PAGE_SIZE), (20 * PAGE_SIZE, 21 * PAGE_SIZE)];
+        let mut bitmap = vec![0; 1];
+        let mut next_fit = allocator(&mut bitmap, &areas);
+        assert_eq!(next_fit.allocate_contiguous_frames(2), Ok(Frame::from_number(2)));
+        assert_eq!(next_fit.allocate_contiguous_frames(8).err(), Some(FrameAllocError::OutOfMemory));
+
+        let mut bitmap = vec![0; 1];
+        let mut best_fit = allocator(&mut bitmap, &areas);
+        best_fit.set_alloc_policy(AllocPolicy::BestFit);
+        assert_eq!(best_fit.allocate_contiguous_frames(2), Ok(Frame::from_number(12)));
+        assert_eq!(best_fit.allocate_contiguous_frames(8), Ok(Frame::from_number(2)));
+        // single frames are allocated first-fit
+        assert_eq!(best_fit.allocate_frame().unwrap().number(), 20);
+    }
+
+    #[test]
+    fn down_mode_allocates_high_frames_first() {
+        let mut bitmap = vec![0; 8];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x9f000), (0x100000, 0x140000)]);
+        allocator.set_scan_direction(ScanDirection::Down);
+
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 0x13f);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 0x13e);
+        allocator.deallocate_frame(Frame::from_number(0x13f));
+        // next-fit, so the freed frame is only found after wrapping around
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 0x13d);
+
+        let free_frames = allocator.free_frame_count();
+        let mut allocated = 0;
+        let mut previous = 0x13d;
+        while let Some(frame) = allocator.allocate_frame() {
+            if frame.number() != 0x13f {
+                assert!(frame.number() < previous);
+                previous = frame.number();
+            }
+            allocated += 1;
+        }
+        assert_eq!(allocated, free_frames);
+        assert!(allocator.allocate_frame().is_none());
+    }
+
+    #[test]
+    fn aligned_frames_for_huge_page() {
+        let mut bitmap = vec![0; 32];
+        let mut allocator = allocator(&mut bitmap, &[(0, 0x800000)]);
+        allocator.allocate_frame().unwrap();
+
+        let mut range = allocator.allocate_frames_aligned(512, 512).unwrap();
+        assert_eq!(range.len(), 512);
+        let start = range.next().unwrap();
+        assert_eq!(start.number() % 512, 0);
+        assert_eq!(start.number(), 512);
+        assert_eq!(allocator.allocate_frames_aligned(4, 3).err(), Some(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.allocate_frames_aligned(4, 0).err(), Some(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    fn last_failure_is_recorded() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 8 * PAGE_SIZE)]);
+        allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.allocate_contiguous_frames(7), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.last_failure(), Some(&LastFailure {
+            kind: FrameAllocError::OutOfMemory,
+            requested: 7,
+            free_at_time: 6,
+            cursor: 2,
+        }));
+
+        allocator.allocate_frame().unwrap();
+        assert!(allocator.last_failure().is_none());
+
+        assert_eq!(allocator.allocate_frame_in_range(Frame::from_number(5), Frame::from_number(3)),
+                   Err(FrameAllocError::InvalidRange));
+        let failure = *allocator.last_failure().unwrap();
+        assert_eq!((failure.kind, failure.requested, failure.free_at_time), (FrameAllocError::InvalidRange, 1, 5));
+    }
+
+    #[test]
+    fn kernel_bounds_cover_all_sections() {
+        let sections = [(0x104000, 0x2000), (0x100000, 0x10), (0x110000, 0x1234), (0x106000, 0x8000)];
+        let (start, end) = kernel_bounds(sections.iter().cloned()).unwrap();
+        assert_eq!((start, end), (0x100000, 0x111234));
+        assert!(sections.iter().all(|&(addr, size)| start <= addr && addr + size <= end));
+        assert_eq!(kernel_bounds(None.into_iter()), None);
+    }
+
+    #[test]
+    fn largest_free_run_is_measured() {
+        let mut bitmap = vec![0; 4];
+        let mut allocator = allocator(&mut bitmap, &[(2 * PAGE_SIZE, 5 * PAGE_SIZE), (10 * PAGE_SIZE, 60 * PAGE_SIZE),
+                                                     (64 * PAGE_SIZE, 200 * PAGE_SIZE)]);
+        assert_eq!(allocator.largest_free_run(), 136);
+
+        allocator.allocate_frame_at(Frame::from_number(100)).unwrap();
+        assert_eq!(allocator.largest_free_run(), 99);
+        while allocator.allocate_frame().is_some() {}
+        assert_eq!(allocator.largest_free_run(), 0);
+    }
+
+    #[test]
+    fn managed_range_ends_with_last_area() {
+        let mut bitmap = vec![0; 64];
+        let allocator = allocator(&mut bitmap, &[(0x100000, 0x7fe000), (0x1000, 0x9f000)]);
+        assert_eq!(allocator.managed_range(), (0, 0x7fe000));
+        assert_eq!(allocator.managed_range().1 - allocator.managed_range().0, allocator.total_memory_bytes());
+
+        let mut bitmap = vec![0; 2];
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        let allocator = BitmapFrameAllocator::from_areas_with_base(&mut bitmap, reserved, Frame::from_number(256),
+                                                                   zero, zero, zero, zero,
+                                                                   vec![(0, 0x160000)].into_iter()).unwrap();
+        assert_eq!(allocator.managed_range(), (0x100000, 0x160000));
+    }
+
+    #[test]
+    fn only_usable_areas_are_free() {
+        let mut bitmap = vec![0; 4];
+        let reserved = reserved_bitmap(bitmap.len());
+        let zero = PhysicalAddress::new(0);
+        // a draining iterator cannot be cloned, so it is only walked once
+        let mut usable = vec![(0x80000, 0xa0000), (0x10800, 0x14000)];
+        let allocator = BitmapFrameAllocator::from_areas(&mut bitmap, reserved, zero, zero, zero, zero,
+                                                         usable.drain(..)).unwrap();
+
+        let free: Vec<usize> = allocator.free_frames().map(|frame| frame.number()).collect();
+        assert_eq!(free, (0x11..0x14).chain(0x80..0xa0).collect::<Vec<_>>());
+        assert_eq!(allocator.free_frame_count(), 3 + 0x20);
+        assert_eq!(allocator.last_frame.number(), 0xa0);
+    }
+
+    #[test]
+    fn free_run_histogram_counts_runs() {
+        let mut bitmap = vec![0; 3];
+        let mut allocator = allocator(&mut bitmap, &[(0, 192 * PAGE_SIZE)]);
+        for &frame_number in &[4, 5, 20, 37, 130] {
+            allocator.set_used(frame_number, true);
+        }
+        for frame_number in 64..128 {
+            allocator.set_used(frame_number, true);
+        }
+
+        // free runs: 1..4, 6..20, 21..37, 38..64, 128..130 and 131..191
+        let mut buckets = [7; 32];
+        allocator.free_run_histogram(&mut buckets);
+        assert_eq!(&buckets[..7], &[0, 2, 0, 1, 2, 1, 0]);
+        assert!(buckets[7..].iter().all(|&count| count == 0));
+    }
+
+    static TRACED_EVENTS: Mutex<[Option<FrameEvent>; 8]> = Mutex::new([None, None, None, None,
+                                                                        None, None, None, None]);
+
+    fn record_event(event: FrameEvent) {
+        let mut events = TRACED_EVENTS.lock();
+        let slot = events.iter().position(|event| event.is_none()).expect("too many frame events");
+        events[slot] = Some(event);
+    }
+
+    #[test]
+    fn trace_sees_every_allocation() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        allocator.set_trace(record_event);
+
+        let frame1 = allocator.allocate_frame().unwrap();
+        let frame2 = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(frame1);
+        assert_eq!(allocator.try_deallocate_frame(frame1), Err(FrameAllocError::DoubleFree));
+        let run = allocator.allocate_contiguous_dma32(2).unwrap();
+        allocator.deallocate_frame(frame2);
+        allocator.deallocate_range(Frame::range_inclusive(run, Frame::from_number(run.number() + 1)));
+
+        let events = TRACED_EVENTS.lock();
+        assert_eq!(*events, [Some(FrameEvent::Allocated(Frame::from_number(1))),
+                             Some(FrameEvent::Allocated(Frame::from_number(2))),
+                             Some(FrameEvent::Deallocated(Frame::from_number(1))),
+                             Some(FrameEvent::Allocated(Frame::from_number(3))),
+                             Some(FrameEvent::Allocated(Frame::from_number(4))),
+                             Some(FrameEvent::Deallocated(Frame::from_number(2))),
+                             Some(FrameEvent::Deallocated(Frame::from_number(3))),
+                             Some(FrameEvent::Deallocated(Frame::from_number(4)))]);
+    }
+
+    #[test]
+    fn repeated_frames_are_freed_once() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        let free_frames = allocator.free_frame_count();
+        let frame1 = allocator.allocate_frame().unwrap();
+        let frame2 = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frames_dedup(&[frame1, frame2, frame1]);
+        assert!(!allocator.frame_is_used(frame1.number()));
+        assert!(!allocator.frame_is_used(frame2.number()));
+        assert_eq!(allocator.free_frame_count(), free_frames);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame deallocation failed: Err(DoubleFree)")]
+    fn dedup_still_catches_frames_freed_before() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        let frame1 = allocator.allocate_frame().unwrap();
+        let frame2 = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(frame1);
+        allocator.deallocate_frames_dedup(&[frame2, frame1]);
+    }
+
+    static WATERMARK_HITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_watermark_hit() {
+        WATERMARK_HITS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn low_watermark_fires_once_per_drop() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        let threshold = allocator.free_frame_count() - 3;
+        allocator.set_low_watermark(threshold, count_watermark_hit);
+
+        allocator.allocate_frame().unwrap();
+        allocator.allocate_frame().unwrap();
+        assert_eq!(WATERMARK_HITS.load(Ordering::SeqCst), 0);
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_frame_count(), threshold);
+        assert_eq!(WATERMARK_HITS.load(Ordering::SeqCst), 1);
+        allocator.allocate_frame().unwrap();
+        assert_eq!(WATERMARK_HITS.load(Ordering::SeqCst), 1);
+
+        allocator.deallocate_frame(frame);
+        allocator.allocate_frame().unwrap();
+        assert_eq!(WATERMARK_HITS.load(Ordering::SeqCst), 1);
+        allocator.deallocate_frame(Frame::from_number(1));
+        allocator.deallocate_frame(Frame::from_number(2));
+        allocator.allocate_frame().unwrap();
+        assert_eq!(WATERMARK_HITS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn debug_shows_cursor_and_counts() {
+        let mut bitmap = vec![0; 1];
+        let mut allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+        allocator.allocate_frame().unwrap();
+        assert_eq!(format!("{:?}", allocator),
+                   "BitmapFrameAllocator { next_frame: Frame(0x2, addr=0x2000), last_frame: Frame(0x10, addr=0x10000), \
+                    second_scan: false, free_frames: 14, used_frames: 2 }");
+    }
+
+    #[test]
+    fn bitmap_can_start_above_frame_0() {
+        let mut bitmap = vec![0; 2];
+        let reserved = reserved_bitmap(2);
+        let kernel = PhysicalAddress::new(0x100000);
+        let multiboot = PhysicalAddress::new(0x101000);
+        let mut allocator = BitmapFrameAllocator::from_areas_with_base(&mut bitmap, reserved, Frame::from_number(256),
+                                                                       kernel, kernel, multiboot, multiboot,
+                                                                       vec![(0, 512 * PAGE_SIZE)].into_iter()).unwrap();
+        // frames 256 and 257 hold the kernel and the multiboot structure, the last one is kept
+        assert_eq!(allocator.last_frame.number(), 256 + 2 * BITS_PER_BLOCK - 1);
+        assert_eq!(allocator.free_frame_count(), 2 * BITS_PER_BLOCK - 3);
+        assert_eq!(allocator.get_block_number(256 + BITS_PER_BLOCK), 1);
+        assert_eq!(allocator.first_frame_in_block(1).number(), 256 + BITS_PER_BLOCK);
+        assert!(!allocator.frame_is_used(10) && allocator.frame_is_used(256));
+
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 258);
+        assert_eq!(allocator.allocate_frame_with_hint(AllocHint::PreferLow).unwrap().number(), 259);
+        assert_eq!(allocator.allocate_frame_at(Frame::from_number(100)), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.try_deallocate_frame(Frame::from_number(100)), Err(FrameAllocError::InvalidRange));
+        let mut frames = 2;
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(frame.number() >= 256);
+            frames += 1;
+        }
+        assert_eq!(frames, 2 * BITS_PER_BLOCK - 3);
+        assert!(allocator.free_frames().next().is_none());
+        allocator.deallocate_frame(Frame::from_number(300));
+        assert_eq!(allocator.free_frames().map(|frame| frame.number()).collect::<Vec<_>>(), vec![300]);
+    }
+
+    #[test]
+    fn frames_beyond_bitmap_are_not_used() {
+        let mut bitmap = vec![0; 1];
+        let allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+
+        assert!(allocator.frame_is_used(allocator.last_frame.number()));
+        assert!(!allocator.frame_is_used(10 * BITS_PER_BLOCK));
+        assert!(!allocator.frame_is_reserved(10 * BITS_PER_BLOCK));
+        assert!(!allocator.frame_is_used(core::usize::MAX));
+    }
+
+    #[test]
+    fn frames_past_memory_map_end_are_not_managed() {
+        let mut bitmap = vec![0; 1];
+        let allocator = allocator(&mut bitmap, &[(0, 16 * PAGE_SIZE)]);
+
+        assert!(allocator.contains(Frame::from_number(0)) && allocator.contains(Frame::from_number(15)));
+        assert!(!allocator.contains(Frame::from_number(16)));
+        assert_eq!(allocator.try_frame_is_used(Frame::from_number(0)), Ok(true));
+        assert_eq!(allocator.try_frame_is_used(Frame::from_number(15)), Ok(false));
+        assert_eq!(allocator.try_frame_is_used(Frame::from_number(16)), Err(FrameAllocError::InvalidRange));
+        assert_eq!(allocator.try_frame_is_used(Frame::from_number(core::usize::MAX)), Err(FrameAllocError::InvalidRange));
+    }
+}
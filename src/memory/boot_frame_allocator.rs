@@ -0,0 +1,147 @@
+use core::iter::Map;
+
+use super::{Frame, FrameAllocator, FrameAllocError};
+use super::paging::PhysicalAddress;
+use multiboot2::{MemoryArea, MemoryAreaIter};
+
+/// `(start, end)` address pairs of the usable memory areas in the multiboot memory map
+pub type MemoryAreas = Map<MemoryAreaIter, fn(&'static MemoryArea) -> (usize, usize)>;
+
+fn area_bounds(area: &'static MemoryArea) -> (usize, usize) {
+    (area.base_addr as usize, (area.base_addr + area.length) as usize)
+}
+
+/// Bump allocator over the multiboot memory map for use during early boot.
+/// It never takes frames back, so it only implements `FrameAllocator`.
+pub struct BootFrameAllocator<I> {
+    next_free_frame: Frame,
+    current_area: Option<(usize, usize)>,
+    areas: I,
+    kernel_start: Frame,
+    kernel_end: Frame,
+    multiboot_start: Frame,
+    multiboot_end: Frame,
+}
+
+impl BootFrameAllocator<MemoryAreas> {
+    pub fn new(kernel_start: usize, kernel_end: usize,
+               multiboot_start: usize, multiboot_end: usize,
+               memory_areas: MemoryAreaIter) -> BootFrameAllocator<MemoryAreas>
+    {
+        let memory_areas = memory_areas.map(area_bounds as fn(&'static MemoryArea) -> (usize, usize));
+        BootFrameAllocator::from_areas(kernel_start, kernel_end, multiboot_start, multiboot_end, memory_areas)
+    }
+}
+
+impl<I> BootFrameAllocator<I> where I: Iterator<Item = (usize, usize)> + Clone {
+    /// Builds the allocator from `(start, end)` address pairs of usable memory areas
+    fn from_areas(kernel_start: usize, kernel_end: usize,
+                  multiboot_start: usize, multiboot_end: usize,
+                  memory_areas: I) -> BootFrameAllocator<I>
+    {
+        let mut allocator = BootFrameAllocator {
+            next_free_frame: Frame::containing_address(PhysicalAddress::new(0)),
+            current_area: None,
+            areas: memory_areas,
+            kernel_start: Frame::containing_address(PhysicalAddress::new(kernel_start)),
+            kernel_end: Frame::containing_address(PhysicalAddress::new(kernel_end)),
+            multiboot_start: Frame::containing_address(PhysicalAddress::new(multiboot_start)),
+            multiboot_end: Frame::containing_address(PhysicalAddress::new(multiboot_end)),
+        };
+        allocator.choose_next_area();
+        allocator
+    }
+
+    /// Moves on to the lowest area which still has frames at or after `next_free_frame`
+    fn choose_next_area(&mut self) {
+        let next_free_frame = self.next_free_frame.number();
+        self.current_area = self.areas.clone()
+            .filter(|&(start, end)| end > start && Frame::containing_address(PhysicalAddress::new(end - 1)).number() >= next_free_frame)
+            .min_by_key(|&(start, _)| start);
+
+        if let Some((start, _)) = self.current_area {
+            let start_frame = Frame::containing_address(PhysicalAddress::new(start));
+            if self.next_free_frame < start_frame {
+                self.next_free_frame = start_frame;
+            }
+        }
+    }
+
+    /// Hands out the next `count` frames which lie in one area and
+    /// contain neither the kernel nor the multiboot structure
+    fn allocate_run(&mut self, count: usize) -> Option<Frame> {
+        loop {
+            let (_, area_end) = self.current_area?;
+            let first = self.next_free_frame.number();
+            let last = first + count - 1;
+            let area_last_frame = Frame::containing_address(PhysicalAddress::new(area_end - 1)).number();
+
+            if last > area_last_frame {
+                self.next_free_frame = Frame::from_number(area_last_frame + 1);
+                self.choose_next_area();
+            } else if first <= self.kernel_end.number() && last >= self.kernel_start.number() {
+                self.next_free_frame = Frame::from_number(self.kernel_end.number() + 1);
+            } else if first <= self.multiboot_end.number() && last >= self.multiboot_start.number() {
+                self.next_free_frame = Frame::from_number(self.multiboot_end.number() + 1);
+            } else {
+                self.next_free_frame = Frame::from_number(last + 1);
+                return Some(Frame::from_number(first))
+            }
+        }
+    }
+}
+
+impl<I> FrameAllocator for BootFrameAllocator<I> where I: Iterator<Item = (usize, usize)> + Clone {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_run(1)
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::InvalidRange);
+        }
+        self.allocate_run(count).ok_or(FrameAllocError::OutOfMemory)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    fn allocator(kernel: (usize, usize), multiboot: (usize, usize),
+                 areas: &[(usize, usize)]) -> BootFrameAllocator<::std::vec::IntoIter<(usize, usize)>> {
+        BootFrameAllocator::from_areas(kernel.0, kernel.1, multiboot.0, multiboot.1, areas.to_vec().into_iter())
+    }
+
+    #[test]
+    fn skips_kernel_multiboot_and_gaps() {
+        let mut allocator = allocator((0x2000, 0x3fff), (0x5000, 0x5fff), &[(0x1000, 0x7000), (0x9000, 0xb000)]);
+
+        let frames: Vec<usize> = (0..5).map(|_| allocator.allocate_frame().unwrap().number()).collect();
+        assert_eq!(frames, vec![1, 4, 6, 9, 10]);
+        assert!(allocator.allocate_frame().is_none());
+    }
+
+    #[test]
+    fn contiguous_run_stays_in_one_area() {
+        let mut allocator = allocator((0, 0), (0, 0), &[(0x0, 0x4000), (0x10000, 0x20000)]);
+
+        assert_eq!(allocator.allocate_contiguous_frames(4).unwrap().number(), 0x10);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 0x14);
+        assert_eq!(allocator.allocate_contiguous_frames(0x20), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(allocator.allocate_contiguous_frames(0), Err(FrameAllocError::InvalidRange));
+    }
+
+    #[test]
+    fn usable_as_generic_frame_allocator() {
+        fn allocate<A: FrameAllocator>(allocator: &mut A) -> Option<Frame> {
+            allocator.allocate_frame()
+        }
+
+        let mut allocator = allocator((0, 0), (0, 0), &[(0x0, 0x3000)]);
+        assert_eq!(allocate(&mut allocator).unwrap().number(), 1);
+        assert_eq!(allocate(&mut &mut allocator).unwrap().number(), 2);
+        assert!(allocate(&mut allocator).is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use core;
+
+use memory::paging::{PAGE_SIZE, PhysicalAddress};
+use super::{Frame, FrameRange, FrameAllocator, FrameDeallocator, FrameAllocError, align_up};
+use multiboot2::MemoryAreaIter;
+
+/// Largest block is `2^MAX_ORDER` frames, i.e. 4 MiB
+pub const MAX_ORDER: usize = 10;
+
+/// End of a free list
+const NIL: usize = core::usize::MAX;
+/// Order of a node which does not start a free block
+const NOT_FREE: u8 = core::u8::MAX;
+
+/// Free list links of one frame, only used while the frame starts a free block
+#[derive(Debug, Clone, Copy)]
+pub struct BuddyNode {
+    next: usize,
+    prev: usize,
+    order: u8,
+}
+
+impl BuddyNode {
+    pub const fn new() -> BuddyNode {
+        BuddyNode {
+            next: NIL,
+            prev: NIL,
+            order: NOT_FREE,
+        }
+    }
+}
+
+/// Buddy allocator with a doubly linked free list per order. Blocks of
+/// `2^order` frames start at a multiple of `2^order`, and a freed block is
+/// merged with its buddy whenever the buddy is free as well.
+pub struct BuddyFrameAllocator<'a> {
+    /// One node per managed frame, so it also limits the memory which is managed
+    nodes: &'a mut [BuddyNode],
+    free_lists: [usize; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl<'a> BuddyFrameAllocator<'a> {
+    pub fn new(nodes: &'a mut [BuddyNode], kernel_start: PhysicalAddress, kernel_end: PhysicalAddress,
+               multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+               memory_areas: MemoryAreaIter) -> BuddyFrameAllocator<'a>
+    {
+        let memory_areas = memory_areas.map(|area| (area.base_addr as usize, (area.base_addr + area.length) as usize));
+        BuddyFrameAllocator::from_areas(nodes, kernel_start, kernel_end, multiboot_start, multiboot_end, memory_areas)
+    }
+
+    /// Like `new`, but with areas given as `(start, end)` addresses, `end` being exclusive.
+    /// Frames holding the kernel or the multiboot structure are never freed.
+    pub fn from_areas<I>(nodes: &'a mut [BuddyNode], kernel_start: PhysicalAddress, kernel_end: PhysicalAddress,
+                         multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                         memory_areas: I) -> BuddyFrameAllocator<'a>
+        where I: Iterator<Item = (usize, usize)>
+    {
+        for node in nodes.iter_mut() {
+            *node = BuddyNode::new();
+        }
+        let managed = FrameRange::new(Frame::from_number(0), Frame::from_number(nodes.len()));
+        let mut allocator = BuddyFrameAllocator {
+            nodes: nodes,
+            free_lists: [NIL; MAX_ORDER + 1],
+            free_frames: 0,
+        };
+
+        let kernel = FrameRange::containing(kernel_start, kernel_end);
+        let multiboot = FrameRange::containing(multiboot_start, multiboot_end);
+        // only frames which lie entirely inside an area are usable
+        for (area_start, area_end) in memory_areas {
+            let area = FrameRange::new(Frame::from_number(align_up(area_start, PAGE_SIZE) / PAGE_SIZE),
+                                       Frame::from_number(area_end / PAGE_SIZE));
+            let area = match area.intersection(&managed) {
+                Some(area) => area,
+                None => continue,
+            };
+            let (below_kernel, above_kernel) = area.difference(&kernel);
+            for part in below_kernel.into_iter().chain(above_kernel) {
+                let (below_multiboot, above_multiboot) = part.difference(&multiboot);
+                for usable in below_multiboot.into_iter().chain(above_multiboot) {
+                    allocator.free_range(usable);
+                }
+            }
+        }
+        allocator
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of the given order
+    pub fn free_block_count(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_lists[order];
+        while block != NIL {
+            count += 1;
+            block = self.nodes[block].next;
+        }
+        count
+    }
+
+    /// Allocates a block of `2^order` frames, aligned to its size, and returns its first frame
+    pub fn allocate_order(&mut self, order: usize) -> Result<Frame, FrameAllocError> {
+        if order > MAX_ORDER {
+            return Err(FrameAllocError::InvalidRange)
+        }
+        let mut block_order = (order..MAX_ORDER + 1).find(|&o| self.free_lists[o] != NIL)
+                                                    .ok_or(FrameAllocError::OutOfMemory)?;
+        let block = self.free_lists[block_order];
+        self.remove(block, block_order);
+        // hand the upper halves back until the block has the requested size
+        while block_order > order {
+            block_order -= 1;
+            self.push(block + (1 << block_order), block_order);
+        }
+        self.free_frames -= 1 << order;
+        Ok(Frame::from_number(block))
+    }
+
+    /// Frees a block allocated with `allocate_order`
+    pub fn deallocate_order(&mut self, frame: Frame, order: usize) {
+        let block = frame.number();
+        assert!(order <= MAX_ORDER, "order {} exceeds the maximum order {}", order, MAX_ORDER);
+        assert!(block % (1 << order) == 0, "frame {} does not start a block of order {}", block, order);
+        assert!(block + (1 << order) <= self.nodes.len(), "frame {} exceeds managed memory", block);
+        if let Some(free_block) = self.free_block_containing(block) {
+            panic!("double free of frame {}, it is part of the free block at {}", block, free_block);
+        }
+        if let Some(frame) = (block..block + (1 << order)).find(|&frame| self.nodes[frame].order != NOT_FREE) {
+            panic!("double free of frame {}", frame);
+        }
+        self.free_block(block, order);
+    }
+
+    /// First frame of the free block which contains `frame`, if there is one.
+    /// Only the aligned blocks of every order can contain it.
+    fn free_block_containing(&self, frame: usize) -> Option<usize> {
+        (0..MAX_ORDER + 1).map(|order| frame & !((1 << order) - 1))
+                          .find(|&block| {
+                              let order = self.nodes[block].order;
+                              order != NOT_FREE && frame < block + (1 << order)
+                          })
+    }
+
+    /// Frees every frame of the range, as the largest aligned blocks which fit
+    fn free_range(&mut self, range: FrameRange) {
+        let mut block = range.start.number();
+        let end = range.end.number();
+        while block < end {
+            let mut order = 0;
+            while order < MAX_ORDER && block % (2 << order) == 0 && block + (2 << order) <= end {
+                order += 1;
+            }
+            self.free_block(block, order);
+            block += 1 << order;
+        }
+    }
+
+    /// Puts a block into the free lists, merging it with its buddy as long as that is free
+    fn free_block(&mut self, mut block: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if buddy >= self.nodes.len() || self.nodes[buddy].order != order as u8 {
+                break
+            }
+            self.remove(buddy, order);
+            block &= !(1 << order);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    fn push(&mut self, block: usize, order: usize) {
+        let head = self.free_lists[order];
+        self.nodes[block] = BuddyNode {
+            next: head,
+            prev: NIL,
+            order: order as u8,
+        };
+        if head != NIL {
+            self.nodes[head].prev = block;
+        }
+        self.free_lists[order] = block;
+    }
+
+    fn remove(&mut self, block: usize, order: usize) {
+        let node = self.nodes[block];
+        if node.prev == NIL {
+            self.free_lists[order] = node.next;
+        } else {
+            self.nodes[node.prev].next = node.next;
+        }
+        if node.next != NIL {
+            self.nodes[node.next].prev = node.prev;
+        }
+        self.nodes[block] = BuddyNode::new();
+    }
+}
+
+impl<'a> FrameAllocator for BuddyFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_order(0).ok()
+    }
+
+    /// Frames of the block beyond `count` are freed again right away,
+    /// so every frame can be freed on its own
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::InvalidRange)
+        }
+        let order = (0..MAX_ORDER + 1).find(|&order| 1 << order >= count)
+                                      .ok_or(FrameAllocError::InvalidRange)?;
+        let frame = self.allocate_order(order)?;
+        let block_end = frame.number() + (1 << order);
+        self.free_range(FrameRange::new(Frame::from_number(frame.number() + count), Frame::from_number(block_end)));
+        Ok(frame)
+    }
+}
+
+/// Frees a single frame, a block of order 0
+impl<'a> FrameDeallocator for BuddyFrameAllocator<'a> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.deallocate_order(frame, 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Frame 0 holds both the kernel and the multiboot structure
+    fn allocator<'a>(nodes: &'a mut [BuddyNode], areas: &[(usize, usize)]) -> BuddyFrameAllocator<'a> {
+        let zero = PhysicalAddress::new(0);
+        BuddyFrameAllocator::from_areas(nodes, zero, zero, zero, zero, areas.to_vec().into_iter())
+    }
+
+    #[test]
+    fn areas_are_split_into_aligned_blocks() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let allocator = allocator(&mut nodes, &[(0, 16 * PAGE_SIZE)]);
+
+        // frames 1..16 are free: 1, 2..4, 4..8 and 8..16
+        assert_eq!(allocator.free_frame_count(), 15);
+        assert_eq!((0..5).map(|order| allocator.free_block_count(order)).collect::<Vec<_>>(), vec![1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn order_0_buddies_coalesce() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = allocator(&mut nodes, &[(16 * PAGE_SIZE, 32 * PAGE_SIZE)]);
+        assert_eq!(allocator.free_block_count(4), 1);
+
+        let frame1 = allocator.allocate_frame().unwrap();
+        let frame2 = allocator.allocate_frame().unwrap();
+        assert_eq!((frame1.number(), frame2.number()), (16, 17));
+        assert_eq!(allocator.free_block_count(4), 0);
+        assert_eq!(allocator.free_frame_count(), 14);
+
+        allocator.deallocate_frame(frame1);
+        assert_eq!(allocator.free_block_count(0), 1);
+        allocator.deallocate_frame(frame2);
+        assert_eq!(allocator.free_block_count(0), 0);
+        assert_eq!(allocator.free_block_count(4), 1);
+        assert_eq!(allocator.free_frame_count(), 16);
+    }
+
+    #[test]
+    fn order_3_buddies_coalesce() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = allocator(&mut nodes, &[(16 * PAGE_SIZE, 32 * PAGE_SIZE)]);
+
+        let block1 = allocator.allocate_order(3).unwrap();
+        let block2 = allocator.allocate_order(3).unwrap();
+        assert_eq!((block1.number(), block2.number()), (16, 24));
+        assert_eq!(allocator.allocate_order(3), Err(FrameAllocError::OutOfMemory));
+
+        allocator.deallocate_order(block2, 3);
+        assert_eq!(allocator.free_block_count(3), 1);
+        allocator.deallocate_order(block1, 3);
+        assert_eq!(allocator.free_block_count(3), 0);
+        assert_eq!(allocator.free_block_count(4), 1);
+        assert_eq!(allocator.allocate_order(4).unwrap().number(), 16);
+    }
+
+    #[test]
+    fn kernel_and_multiboot_frames_are_not_free() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = BuddyFrameAllocator::from_areas(&mut nodes, PhysicalAddress::new(0x4000), PhysicalAddress::new(0x5fff),
+                                                            PhysicalAddress::new(0x5000), PhysicalAddress::new(0x8fff),
+                                                            vec![(0, 16 * PAGE_SIZE)].into_iter());
+        assert_eq!(allocator.free_frame_count(), 11);
+        while let Some(frame) = allocator.allocate_frame() {
+            assert!(frame.number() < 4 || frame.number() >= 9);
+        }
+    }
+
+    #[test]
+    fn contiguous_frames_can_be_freed_one_by_one() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = allocator(&mut nodes, &[(16 * PAGE_SIZE, 32 * PAGE_SIZE)]);
+
+        let frame = allocator.allocate_contiguous_frames(5).unwrap();
+        assert_eq!(frame.number(), 16);
+        assert_eq!(allocator.free_frame_count(), 11);
+        allocator.deallocate_contiguous_frames(frame, 5);
+        assert_eq!(allocator.free_block_count(4), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free of frame 17, it is part of the free block at 16")]
+    fn double_free_inside_a_free_block_is_detected() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = allocator(&mut nodes, &[(16 * PAGE_SIZE, 32 * PAGE_SIZE)]);
+        assert_eq!(allocator.free_block_count(4), 1);
+
+        allocator.deallocate_frame(Frame::from_number(17));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free of frame 20")]
+    fn double_free_of_a_block_containing_a_free_block_is_detected() {
+        let mut nodes = vec![BuddyNode::new(); 64];
+        let mut allocator = allocator(&mut nodes, &[(16 * PAGE_SIZE, 32 * PAGE_SIZE)]);
+        let block = allocator.allocate_order(3).unwrap();
+        allocator.deallocate_frame(Frame::from_number(20));
+
+        allocator.deallocate_order(block, 3);
+    }
+}
@@ -0,0 +1,135 @@
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+/// Most frames a `FramePool` can hold
+pub const FRAME_POOL_SIZE: usize = 64;
+
+/// Stash of frames allocated ahead of time, so that code which must not take
+/// the allocator lock, like interrupt handlers, can still get a frame.
+/// `take` and `try_put` never touch an allocator.
+pub struct FramePool {
+    frames: [usize; FRAME_POOL_SIZE],
+    len: usize,
+    capacity: usize,
+}
+
+impl FramePool {
+    /// Creates a pool holding up to `capacity` frames and fills it from `allocator`
+    pub fn with_capacity<A: FrameAllocator>(allocator: &mut A, capacity: usize) -> FramePool {
+        assert!(capacity <= FRAME_POOL_SIZE, "frame pool can hold at most {} frames", FRAME_POOL_SIZE);
+        let mut pool = FramePool {
+            frames: [0; FRAME_POOL_SIZE],
+            len: 0,
+            capacity: capacity,
+        };
+        let _ = pool.refill(allocator);
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn take(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            None
+        } else {
+            self.len -= 1;
+            Some(Frame::from_number(self.frames[self.len]))
+        }
+    }
+
+    /// Puts a frame back, or returns it if the pool is full
+    pub fn try_put(&mut self, frame: Frame) -> Result<(), Frame> {
+        if self.len == self.capacity {
+            Err(frame)
+        } else {
+            self.frames[self.len] = frame.number();
+            self.len += 1;
+            Ok(())
+        }
+    }
+
+    /// Puts a frame back, freeing it in `allocator` if the pool is full
+    pub fn put<A: FrameDeallocator>(&mut self, frame: Frame, allocator: &mut A) {
+        if let Err(frame) = self.try_put(frame) {
+            allocator.deallocate_frame(frame);
+        }
+    }
+
+    /// Tops the pool back up to its capacity
+    pub fn refill<A: FrameAllocator>(&mut self, allocator: &mut A) -> Result<(), FrameAllocError> {
+        while self.len < self.capacity {
+            let frame = allocator.try_allocate_frame()?;
+            self.frames[self.len] = frame.number();
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    /// Frees every frame in the pool
+    pub fn drain_into<A: FrameDeallocator>(&mut self, allocator: &mut A) {
+        while let Some(frame) = self.take() {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::MockAllocator;
+
+    #[test]
+    fn refill_after_partial_drain() {
+        let mut allocator = MockAllocator::new(100);
+        let mut pool = FramePool::with_capacity(&mut allocator, 4);
+        assert_eq!(pool.len(), 4);
+
+        assert_eq!(pool.take().unwrap().number(), 3);
+        assert_eq!(pool.take().unwrap().number(), 2);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.refill(&mut allocator), Ok(()));
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.take().unwrap().number(), 5);
+        assert_eq!(allocator.used_count(), 6);
+    }
+
+    #[test]
+    fn refill_reports_out_of_memory() {
+        let mut allocator = MockAllocator::new(3);
+        let mut pool = FramePool::with_capacity(&mut allocator, 4);
+
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.refill(&mut allocator), Err(FrameAllocError::OutOfMemory));
+    }
+
+    #[test]
+    fn put_beyond_capacity_frees_frame() {
+        let mut allocator = MockAllocator::new(100);
+        let mut pool = FramePool::with_capacity(&mut allocator, 2);
+
+        assert_eq!(pool.try_put(Frame::from_number(50)), Err(Frame::from_number(50)));
+        pool.put(Frame::from_number(50), &mut allocator);
+        assert_eq!(allocator.freed, vec![50]);
+
+        let frame = pool.take().unwrap();
+        pool.put(frame, &mut allocator);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(allocator.freed, vec![50]);
+    }
+
+    #[test]
+    fn drain_into_frees_every_frame() {
+        let mut allocator = MockAllocator::new(100);
+        let mut pool = FramePool::with_capacity(&mut allocator, 3);
+
+        pool.drain_into(&mut allocator);
+        assert_eq!(pool.len(), 0);
+        assert!(pool.take().is_none());
+        assert_eq!(allocator.freed, vec![2, 1, 0]);
+    }
+}
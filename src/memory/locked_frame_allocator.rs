@@ -0,0 +1,88 @@
+use spin::Mutex;
+
+use super::bitmap_frame_allocator::BitmapFrameAllocator;
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+/// `BitmapFrameAllocator` behind a spinlock, so that it can be shared between cores.
+/// Every call takes the lock, allocation works through a shared reference.
+pub struct LockedFrameAllocator<'a> {
+    allocator: Mutex<BitmapFrameAllocator<'a>>,
+}
+
+impl<'a> LockedFrameAllocator<'a> {
+    pub fn new(allocator: BitmapFrameAllocator<'a>) -> LockedFrameAllocator<'a> {
+        LockedFrameAllocator {
+            allocator: Mutex::new(allocator),
+        }
+    }
+
+    pub fn allocate_frame(&self) -> Option<Frame> {
+        self.allocator.lock().allocate_frame()
+    }
+
+    pub fn try_allocate_frame(&self) -> Result<Frame, FrameAllocError> {
+        self.allocator.lock().try_allocate_frame()
+    }
+
+    pub fn deallocate_frame(&self, frame: Frame) {
+        self.allocator.lock().deallocate_frame(frame)
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.allocator.lock().free_frame_count()
+    }
+}
+
+impl<'a, 'b> FrameAllocator for &'b LockedFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.lock().allocate_frame()
+    }
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        self.allocator.lock().try_allocate_frame()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        self.allocator.lock().allocate_contiguous_frames(count)
+    }
+}
+
+impl<'a, 'b> FrameDeallocator for &'b LockedFrameAllocator<'a> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.allocator.lock().deallocate_frame(frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::locked_allocator;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn works_through_shared_reference() {
+        let allocator = locked_allocator(8);
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.number(), 1);
+
+        let mut shared = allocator;
+        assert_eq!(shared.allocate_contiguous_frames(2), Ok(Frame::from_number(2)));
+        shared.deallocate_frame(frame);
+        assert_eq!(allocator.free_frame_count(), 5);
+    }
+
+    #[test]
+    fn concurrent_allocations_are_unique() {
+        let allocator = locked_allocator(128);
+        let threads: Vec<_> = (0..2).map(|_| thread::spawn(move || {
+            (0..50).map(|_| allocator.allocate_frame().unwrap().number()).collect::<Vec<_>>()
+        })).collect();
+
+        let mut frames: Vec<usize> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), 100);
+        assert_eq!(allocator.free_frame_count(), 27);
+    }
+}
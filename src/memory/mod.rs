@@ -2,89 +2,769 @@ pub mod paging;
 pub mod heap_allocator;
 
 mod bitmap_frame_allocator;
+mod buddy_frame_allocator;
+mod boot_frame_allocator;
+mod tagged_frame_allocator;
+mod scrubbing_allocator;
+mod frame_pool;
+mod allocated_frame;
+mod ref_counted_allocator;
+mod locked_frame_allocator;
+mod quota_allocator;
 mod stack_allocator;
 
 use self::bitmap_frame_allocator::BitmapFrameAllocator;
 
-use self::paging::{PAGE_SIZE, PhysicalAddress, Page, ActivePageTable};
+use self::paging::{PAGE_SIZE, PhysicalAddress, VirtualAddress, ActivePageTable};
 
 use self::heap_allocator::{HEAP_START, HEAP_SIZE};
 
 use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::control::{Cr0, Cr0Flags};
 
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 
-use multiboot2::{MemoryAreaIter, ElfSectionsTag, MemoryMapTag, BootInformation};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use core::fmt;
+
+use core::marker::PhantomData;
+
+use core::ops::{Add, AddAssign, Sub};
+
+use core::convert::TryFrom;
+
+use core::num::NonZeroUsize;
+
+use multiboot2::{MemoryAreaIter, ElfSectionIter, ElfSectionsTag, MemoryMapTag, BootInformation};
 
 pub use self::stack_allocator::Stack;
+pub use self::boot_frame_allocator::BootFrameAllocator;
+pub use self::buddy_frame_allocator::{BuddyFrameAllocator, BuddyNode};
+pub use self::tagged_frame_allocator::{TaggedFrameAllocator, MemOwner};
+pub use self::scrubbing_allocator::{ScrubbingAllocator, FrameAccess, OffsetMapping, ScrubPattern};
+pub use self::frame_pool::FramePool;
+pub use self::allocated_frame::AllocatedFrame;
+pub use self::ref_counted_allocator::{RefCountedAllocator, FrameRefCounter};
+pub use self::locked_frame_allocator::LockedFrameAllocator;
+pub use self::quota_allocator::QuotaAllocator;
 
 use self::stack_allocator::StackAllocator;
 
 const STACK_ALLOCATOR_PAGES: usize = 100;
 
-static ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+static ALLOCATOR: Mutex<Option<BitmapFrameAllocator<'static>>> = Mutex::new(None);
 
-/// Init memory allocator
-/// Must be called once, and only once,
-pub unsafe fn frame_allocator_init(kernel_start: usize, kernel_end: usize, 
-                   multiboot_start: usize, multiboot_end: usize, 
-                   memory_areas: MemoryAreaIter) {
-    *ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(&mut bitmap_frame_allocator::BITMAP, 
-                             kernel_start, kernel_end, multiboot_start, multiboot_end, memory_areas));
+/// Set by the first call to `init_frame_allocator`, which claims the static bitmaps
+static ALLOCATOR_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the global frame allocator.
+/// Only the first successful call installs it, later ones return `AlreadyInitialized`.
+pub fn init_frame_allocator(kernel_start: PhysicalAddress, kernel_end: PhysicalAddress,
+                            multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                            memory_areas: MemoryAreaIter) -> Result<(), FrameAllocError> {
+    install_new_frame_allocator(|| {
+        // Safe because only one caller gets here at a time, and only until an
+        // allocator is installed, so nothing else borrows the bitmaps
+        unsafe {
+            BitmapFrameAllocator::try_new(&mut bitmap_frame_allocator::BITMAP,
+                                          &mut bitmap_frame_allocator::RESERVED,
+                                          kernel_start, kernel_end, multiboot_start, multiboot_end, memory_areas)
+        }
+    })
 }
 
-pub fn allocate_frame() -> Option<Frame> {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
-        allocator.allocate_frame()
+/// Like `init_frame_allocator`, but finds the kernel from its ELF sections
+pub fn init_frame_allocator_from_elf_sections(elf_sections: ElfSectionIter,
+                                              multiboot_start: PhysicalAddress, multiboot_end: PhysicalAddress,
+                                              memory_areas: MemoryAreaIter) -> Result<(), FrameAllocError> {
+    install_new_frame_allocator(|| {
+        // Safe for the same reason as in `init_frame_allocator`
+        unsafe {
+            BitmapFrameAllocator::from_elf_sections(&mut bitmap_frame_allocator::BITMAP,
+                                                    &mut bitmap_frame_allocator::RESERVED,
+                                                    elf_sections, multiboot_start, multiboot_end, memory_areas)
+        }
+    })
+}
+
+/// Claims the global allocator and installs the one `create` returns.
+/// If `create` fails the claim is given up again, so a later call can retry.
+fn install_new_frame_allocator<F>(create: F) -> Result<(), FrameAllocError>
+    where F: FnOnce() -> Result<BitmapFrameAllocator<'static>, FrameAllocError>
+{
+    claim_frame_allocator()?;
+    match create() {
+        Ok(allocator) => {
+            install_frame_allocator(allocator);
+            Ok(())
+        },
+        Err(error) => {
+            ALLOCATOR_INITIALIZED.store(false, Ordering::SeqCst);
+            Err(error)
+        },
+    }
+}
+
+fn claim_frame_allocator() -> Result<(), FrameAllocError> {
+    if ALLOCATOR_INITIALIZED.swap(true, Ordering::SeqCst) {
+        Err(FrameAllocError::AlreadyInitialized)
     } else {
-        panic!("frame allocator not initialized");
+        Ok(())
+    }
+}
+
+fn install_frame_allocator(allocator: BitmapFrameAllocator<'static>) {
+    *ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Locks the global frame allocator, which is `None` until `init_frame_allocator` was called
+pub fn frame_allocator() -> MutexGuard<'static, Option<BitmapFrameAllocator<'static>>> {
+    ALLOCATOR.lock()
+}
+
+/// Drops the global allocator so that tests can initialize it again
+#[cfg(test)]
+fn reset_frame_allocator() {
+    *ALLOCATOR.lock() = None;
+    ALLOCATOR_INITIALIZED.store(false, Ordering::SeqCst);
+}
+
+pub fn allocate_frame() -> Option<Frame> {
+    match *frame_allocator() {
+        Some(ref mut allocator) => allocator.allocate_frame(),
+        None => panic!("frame allocator not initialized"),
+    }
+}
+
+pub fn try_allocate_frame() -> Result<Frame, FrameAllocError> {
+    match *frame_allocator() {
+        Some(ref mut allocator) => allocator.try_allocate_frame(),
+        None => Err(FrameAllocError::Uninitialized),
     }
 }
 
 pub fn deallocate_frame(frame: Frame) {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
-        allocator.deallocate_frame(frame)
-    } else {
-        panic!("frame allocator not initialized");
+    match *frame_allocator() {
+        Some(ref mut allocator) => allocator.deallocate_frame(frame),
+        None => panic!("frame allocator not initialized"),
+    }
+}
+
+/// Handle to the global frame allocator, e.g. to share it between several
+/// `QuotaAllocator`s. Every call takes the lock and behaves like the free
+/// functions above.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        allocate_frame()
+    }
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        try_allocate_frame()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        match *frame_allocator() {
+            Some(ref mut allocator) => allocator.allocate_contiguous_frames(count),
+            None => Err(FrameAllocError::Uninitialized),
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Frame {
+impl FrameDeallocator for GlobalFrameAllocator {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        deallocate_frame(frame)
+    }
+}
+
+/// x86_64 physical addresses have at most 52 bits
+pub const MAX_PHYSICAL_ADDRESS: usize = 1 << 52;
+
+/// Rounds `addr` down to a multiple of `align`, which must be a power of two
+pub fn align_down(addr: usize, align: usize) -> usize {
+    assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+    addr & !(align - 1)
+}
+
+/// Rounds `addr` up to a multiple of `align`, which must be a power of two.
+/// Addresses above the highest multiple saturate to it instead of wrapping to 0.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    checked_align_up(addr, align).unwrap_or(align_down(core::usize::MAX, align))
+}
+
+/// Like `align_up`, but `None` if the result does not fit into a `usize`
+pub fn checked_align_up(addr: usize, align: usize) -> Option<usize> {
+    assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+    addr.checked_add(align - 1).map(|addr| addr & !(align - 1))
+}
+
+pub fn is_aligned(addr: usize, align: usize) -> bool {
+    align_down(addr, align) == addr
+}
+
+/// Size of the frames a `PhysFrame` stands for
+pub trait PageSize: Copy + Eq + Ord {
+    const SIZE: usize;
+}
+
+/// Regular 4 KiB frame, mapped by a P1 entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size4KiB {}
+
+/// Huge frame, mapped by a P2 entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size2MiB {}
+
+/// Huge frame, mapped by a P3 entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size1GiB {}
+
+impl PageSize for Size4KiB {
+    const SIZE: usize = PAGE_SIZE;
+}
+
+impl PageSize for Size2MiB {
+    const SIZE: usize = PAGE_SIZE * 512;
+}
+
+impl PageSize for Size1GiB {
+    const SIZE: usize = PAGE_SIZE * 512 * 512;
+}
+
+/// Physical frame of `S::SIZE` bytes, numbered in units of its own size
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysFrame<S: PageSize = Size4KiB> {
     number: usize,
+    size: PhantomData<S>,
 }
 
-impl Frame {
+/// Regular 4 KiB frame, the one every frame allocator deals in
+pub type Frame = PhysFrame<Size4KiB>;
+
+/// 2 MiB frame backing a huge page
+pub type HugeFrame = PhysFrame<Size2MiB>;
+
+impl<S: PageSize> PhysFrame<S> {
+    pub const fn from_number(number: usize) -> PhysFrame<S> {
+        PhysFrame {
+            number: number,
+            size: PhantomData,
+        }
+    }
+
     pub fn number(&self) -> usize {
         self.number
     }
 
-    pub fn containing_address(address: PhysicalAddress) -> Frame {
-        Frame{ number: address / PAGE_SIZE }
+    pub const fn containing_address(address: PhysicalAddress) -> PhysFrame<S> {
+        PhysFrame::from_number(address.as_usize() / S::SIZE)
+    }
+
+    /// `None` if the address is beyond `MAX_PHYSICAL_ADDRESS`, e.g. the end
+    /// of a bogus memory area
+    pub fn checked_containing_address(address: usize) -> Option<PhysFrame<S>> {
+        if address <= MAX_PHYSICAL_ADDRESS {
+            Some(PhysFrame::from_number(address / S::SIZE))
+        } else {
+            None
+        }
+    }
+
+    /// `None` unless the address is aligned to the frame size
+    pub fn from_start_address(address: PhysicalAddress) -> Option<PhysFrame<S>> {
+        if is_aligned(address.as_usize(), S::SIZE) {
+            Some(PhysFrame::containing_address(address))
+        } else {
+            None
+        }
+    }
+
+    pub const fn start_address(&self) -> usize {
+        self.number * S::SIZE
+    }
+
+    /// Address right after the last byte of the frame, i.e. the frame covers
+    /// `start_address()..end_address()`. The last byte is at `end_address() - 1`.
+    pub const fn end_address(&self) -> usize {
+        (self.number + 1) * S::SIZE
+    }
+
+    pub const fn size() -> usize {
+        S::SIZE
+    }
+
+    /// Cache color of the frame if there are `num_colors` colors
+    pub fn color(&self, num_colors: usize) -> usize {
+        self.number % num_colors
+    }
+
+    /// `None` if the frame number overflows
+    pub fn checked_add(&self, rhs: usize) -> Option<PhysFrame<S>> {
+        self.number.checked_add(rhs).map(PhysFrame::from_number)
+    }
+
+    /// `None` below frame 0
+    pub fn checked_sub(&self, rhs: usize) -> Option<PhysFrame<S>> {
+        self.number.checked_sub(rhs).map(PhysFrame::from_number)
+    }
+}
+
+impl Frame {
+    /// Frames from `start` up to but excluding `end`
+    pub fn range(start: Frame, end: Frame) -> FrameRange {
+        FrameRange::new(start, end)
+    }
+
+    pub fn range_inclusive(start: Frame, end: Frame) -> FrameRangeInclusive {
+        FrameRangeInclusive {
+            start: start,
+            end: end,
+        }
+    }
+
+    /// Pointer to the first byte of the frame through the identity mapping
+    /// of physical memory. Unsafe because that mapping only exists during
+    /// early boot, after that the pointer has to be used with `as_ptr_offset`.
+    pub unsafe fn as_ptr_identity(&self) -> *mut u8 {
+        self.as_ptr_offset(0)
+    }
+
+    /// Pointer to the first byte of the frame, with all of physical memory
+    /// mapped starting at the virtual address `physical_memory_offset`.
+    /// Unsafe because nothing checks that the mapping exists.
+    pub unsafe fn as_ptr_offset(&self, physical_memory_offset: usize) -> *mut u8 {
+        (physical_memory_offset + self.start_address()) as *mut u8
+    }
+
+    /// The frame's bytes through the identity mapping, see `as_ptr_identity`.
+    /// The caller also has to make sure the bytes are not accessed any other
+    /// way while the reference lives.
+    pub unsafe fn as_bytes_identity<'b>(&self) -> &'b mut [u8; PAGE_SIZE] {
+        &mut *(self.as_ptr_identity() as *mut [u8; PAGE_SIZE])
+    }
+
+    /// The frame's bytes through the mapping at `physical_memory_offset`,
+    /// with the same requirements as `as_bytes_identity`
+    pub unsafe fn as_bytes_offset<'b>(&self, physical_memory_offset: usize) -> &'b mut [u8; PAGE_SIZE] {
+        &mut *(self.as_ptr_offset(physical_memory_offset) as *mut [u8; PAGE_SIZE])
+    }
+}
+
+impl PhysFrame<Size2MiB> {
+    /// The 512 regular frames covering this huge frame
+    pub fn split(&self) -> FrameRange {
+        let start = Frame::containing_address(PhysicalAddress::new(self.start_address()));
+        FrameRange::new(start, start + 512)
+    }
+
+    /// The regular frames covering the huge frames from `start` up to but excluding `end`
+    pub fn split_range(start: HugeFrame, end: HugeFrame) -> FrameRange {
+        FrameRange::new(start.split().start, end.split().start)
+    }
+
+    /// Inverse of `split`: `None` unless the range is exactly 512 frames
+    /// starting on a 2 MiB boundary
+    pub fn promote(frames: &FrameRange) -> Option<PhysFrame<Size2MiB>> {
+        if frames.len() != 512 {
+            return None
+        }
+        PhysFrame::from_start_address(PhysicalAddress::new(frames.start.start_address()))
+    }
+}
+
+/// Frame with the given number, e.g. one reported by a device. Only checks
+/// that the frame can exist at all, an allocator tells whether it manages it.
+impl<S: PageSize> TryFrom<usize> for PhysFrame<S> {
+    type Error = FrameAllocError;
+
+    fn try_from(number: usize) -> Result<PhysFrame<S>, FrameAllocError> {
+        if number < MAX_PHYSICAL_ADDRESS / S::SIZE {
+            Ok(PhysFrame::from_number(number))
+        } else {
+            Err(FrameAllocError::InvalidRange)
+        }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PhysFrame<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame({:#x}, addr={:#x})", self.number, self.start_address())
+    }
+}
+
+impl<S: PageSize> fmt::Display for PhysFrame<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame {:#x} at {:#x}", self.number, self.start_address())
+    }
+}
+
+impl<S: PageSize> Add<usize> for PhysFrame<S> {
+    type Output = PhysFrame<S>;
+
+    fn add(self, rhs: usize) -> PhysFrame<S> {
+        PhysFrame::from_number(self.number + rhs)
+    }
+}
+
+impl<S: PageSize> AddAssign<usize> for PhysFrame<S> {
+    fn add_assign(&mut self, rhs: usize) {
+        self.number += rhs;
+    }
+}
+
+/// Saturates at frame 0
+impl<S: PageSize> Sub<usize> for PhysFrame<S> {
+    type Output = PhysFrame<S>;
+
+    fn sub(self, rhs: usize) -> PhysFrame<S> {
+        PhysFrame::from_number(self.number.saturating_sub(rhs))
     }
+}
 
-    pub fn start_address(&self) -> PhysicalAddress {
-        self.number * PAGE_SIZE
+/// Number of frames from `rhs` up to `self`, `rhs` must not come after `self`
+impl<S: PageSize> Sub<PhysFrame<S>> for PhysFrame<S> {
+    type Output = usize;
+
+    fn sub(self, rhs: PhysFrame<S>) -> usize {
+        assert!(rhs.number <= self.number, "frame {} comes after frame {}", rhs.number, self.number);
+        self.number - rhs.number
     }
+}
+
+/// Virtual twin of `Frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Page {
+   number: usize,
+}
 
-    pub fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
-        FrameIter {
+impl Page {
+    pub const fn from_number(number: usize) -> Page {
+        Page { number: number }
+    }
+
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Pages from `start` up to but excluding `end`
+    pub fn range(start: Page, end: Page) -> PageRange {
+        PageRange {
             start: start,
             end: end,
         }
     }
 
-    fn clone(&self) -> Frame {
-        Frame {
-            number: self.number
+    pub fn range_inclusive(start: Page, end: Page) -> PageIter {
+        PageIter {
+            start: start,
+            end: end,
         }
     }
+
+    pub fn containing_address(address: VirtualAddress) -> Page {
+        Page { number: address.as_usize() / PAGE_SIZE }
+    }
+
+    /// Page at the same address as the frame, for identity mapped regions
+    pub fn from_frame_identity(frame: Frame) -> Page {
+        Page::containing_address(VirtualAddress::new(frame.start_address()))
+    }
+
+    pub fn start_address(&self) -> VirtualAddress {
+        VirtualAddress::new(self.number * PAGE_SIZE)
+    }
+
+    pub fn p4_index(&self) -> usize {
+        (self.number >> 27) & 0o777
+    }
+    pub fn p3_index(&self) -> usize {
+        (self.number >> 18) & 0o777
+    }
+    pub fn p2_index(&self) -> usize {
+        (self.number >> 9) & 0o777
+    }
+    pub fn p1_index(&self) -> usize {
+        (self.number >> 0) & 0o777
+    }
+}
+
+impl Add<usize> for Page {
+    type Output = Page;
+
+    fn add(self, rhs: usize) -> Page {
+        Page { number: self.number + rhs }
+    }
+}
+
+impl AddAssign<usize> for Page {
+    fn add_assign(&mut self, rhs: usize) {
+        self.number += rhs;
+    }
+}
+
+/// Saturates at page 0
+impl Sub<usize> for Page {
+    type Output = Page;
+
+    fn sub(self, rhs: usize) -> Page {
+        Page { number: self.number.saturating_sub(rhs) }
+    }
 }
 
+/// Number of pages from `rhs` up to `self`, `rhs` must not come after `self`
+impl Sub<Page> for Page {
+    type Output = usize;
+
+    fn sub(self, rhs: Page) -> usize {
+        assert!(rhs.number <= self.number, "page {} comes after page {}", rhs.number, self.number);
+        self.number - rhs.number
+    }
+}
+
+#[derive(Clone)]
+pub struct PageIter {
+    start: Page,
+    end: Page,
+}
+
+impl Iterator for PageIter {
+    type Item = Page;
+
+    fn next(&mut self) -> Option<Page> {
+        if self.start <= self.end {
+            let page = self.start;
+            self.start.number += 1;
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
+
+/// Half-open range of pages, see `Page::range`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageRange {
+    pub start: Page,
+    pub end: Page,
+}
+
+impl Iterator for PageRange {
+    type Item = Page;
+
+    fn next(&mut self) -> Option<Page> {
+        if self.start < self.end {
+            let page = self.start;
+            self.start.number += 1;
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
+
+/// Frame other than frame 0, which holds the real mode IVT and BIOS data area
+/// and is easily mistaken for a null pointer. `Option<NonZeroFrame>` is as
+/// small as a `Frame`. `BitmapFrameAllocator::allocate_nonzero_frame` returns
+/// it, the other allocation methods keep returning `Frame`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NonZeroFrame(NonZeroUsize);
+
+impl NonZeroFrame {
+    /// `None` for frame 0
+    pub fn new(frame: Frame) -> Option<NonZeroFrame> {
+        NonZeroUsize::new(frame.number).map(NonZeroFrame)
+    }
+
+    pub fn get(&self) -> Frame {
+        Frame::from_number(self.0.get())
+    }
+
+    pub fn number(&self) -> usize {
+        self.0.get()
+    }
+}
+
+impl From<NonZeroFrame> for Frame {
+    fn from(frame: NonZeroFrame) -> Frame {
+        frame.get()
+    }
+}
+
+impl TryFrom<Frame> for NonZeroFrame {
+    type Error = FrameAllocError;
+
+    fn try_from(frame: Frame) -> Result<NonZeroFrame, FrameAllocError> {
+        NonZeroFrame::new(frame).ok_or(FrameAllocError::Reserved)
+    }
+}
+
+impl fmt::Debug for NonZeroFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+impl fmt::Display for NonZeroFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.get(), f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameAllocError {
+    /// No free frame satisfies the request
+    OutOfMemory,
+    /// The requested frames lie outside of managed memory
+    InvalidRange,
+    /// The requested frame is already in use
+    AlreadyAllocated,
+    /// The frame being freed is not allocated
+    DoubleFree,
+    /// The global frame allocator has not been set up yet
+    Uninitialized,
+    /// The global frame allocator was already set up
+    AlreadyInitialized,
+    /// The frame is reserved and cannot be freed like an allocated one
+    Reserved,
+    /// The frames being released are not reserved
+    NotReserved,
+    /// The bootloader did not report any usable memory
+    NoMemoryMap,
+    /// Allocating would exceed the limit of a `QuotaAllocator`
+    QuotaExceeded,
+    /// The ELF sections tag does not contain any loaded kernel section
+    NoKernelSections,
+}
+
+/// Must stay object safe, so that an allocator can be stored and passed
+/// around as `&mut dyn FrameAllocator`
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        self.allocate_frame().ok_or(FrameAllocError::OutOfMemory)
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first one
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError>;
+}
+
+/// Allocator which can take frames back
+pub trait FrameDeallocator {
     fn deallocate_frame(&mut self, frame: Frame);
+
+    /// Frees `count` contiguous frames starting at `frame`
+    fn deallocate_contiguous_frames(&mut self, frame: Frame, count: usize) {
+        for frame_number in frame.number()..frame.number() + count {
+            self.deallocate_frame(Frame::from_number(frame_number));
+        }
+    }
+}
+
+/// Allocator which can both hand out frames and take them back
+pub trait FrameManager: FrameAllocator + FrameDeallocator {
+    /// Allocates a frame which is freed when the returned guard is dropped
+    fn allocate_frame_owned(&mut self) -> Option<AllocatedFrame<Self>> where Self: Sized {
+        let frame = self.allocate_frame()?;
+        Some(AllocatedFrame::new(frame, self))
+    }
+}
+
+impl<T: FrameAllocator + FrameDeallocator + ?Sized> FrameManager for T {}
+
+/// Allocator for the tests of the allocator wrappers. Hands out the lowest free
+/// frames of a small memory and remembers which frames were freed. Frames which
+/// were never allocated may be freed as well.
+#[cfg(test)]
+pub struct MockAllocator {
+    pub used: ::std::vec::Vec<bool>,
+    pub freed: ::std::vec::Vec<usize>,
+}
+
+#[cfg(test)]
+impl MockAllocator {
+    /// Memory of `frame_count` frames, all of them free
+    pub fn new(frame_count: usize) -> MockAllocator {
+        MockAllocator::from_frame(0, frame_count)
+    }
+
+    /// Like `new`, but the frames below `first` are already used
+    pub fn from_frame(first: usize, frame_count: usize) -> MockAllocator {
+        MockAllocator {
+            used: (0..frame_count).map(|frame_number| frame_number < first).collect(),
+            freed: ::std::vec::Vec::new(),
+        }
+    }
+
+    pub fn used_count(&self) -> usize {
+        self.used.iter().filter(|&&used| used).count()
+    }
+}
+
+#[cfg(test)]
+impl FrameAllocator for MockAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_contiguous_frames(1).ok()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        let first = (0..(self.used.len() + 1).saturating_sub(count))
+            .find(|&first| !self.used[first..first + count].contains(&true))
+            .ok_or(FrameAllocError::OutOfMemory)?;
+        for used in self.used[first..first + count].iter_mut() {
+            *used = true;
+        }
+        Ok(Frame::from_number(first))
+    }
+}
+
+#[cfg(test)]
+impl FrameDeallocator for MockAllocator {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if let Some(used) = self.used.get_mut(frame.number()) {
+            *used = false;
+        }
+        self.freed.push(frame.number());
+    }
+}
+
+/// Locked bitmap allocator of at most 256 frames for the tests which share
+/// one allocator. Frame 0 is reserved, so the first frame handed out is 1.
+#[cfg(test)]
+pub fn locked_allocator(frames: usize) -> &'static LockedFrameAllocator<'static> {
+    let bitmap = ::std::boxed::Box::leak(vec![0; 4].into_boxed_slice());
+    let reserved = ::std::boxed::Box::leak(vec![0; 4].into_boxed_slice());
+    let zero = PhysicalAddress::new(0);
+    let allocator = BitmapFrameAllocator::from_areas(bitmap, reserved, zero, zero, zero, zero,
+                                                     vec![(0, frames * PAGE_SIZE)].into_iter()).unwrap();
+    ::std::boxed::Box::leak(::std::boxed::Box::new(LockedFrameAllocator::new(allocator)))
+}
+
+impl<'a, A: FrameAllocator + ?Sized> FrameAllocator for &'a mut A {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        (**self).allocate_frame()
+    }
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        (**self).try_allocate_frame()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        (**self).allocate_contiguous_frames(count)
+    }
+}
+
+impl<'a, A: FrameDeallocator + ?Sized> FrameDeallocator for &'a mut A {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        (**self).deallocate_frame(frame)
+    }
+
+    fn deallocate_contiguous_frames(&mut self, frame: Frame, count: usize) {
+        (**self).deallocate_contiguous_frames(frame, count)
+    }
 }
 
 pub struct MemoryController {
@@ -98,25 +778,253 @@ impl MemoryController {
     }
 }
 
-pub struct FrameIter {
+/// Inclusive range of frames
+pub struct FrameRangeInclusive {
     start: Frame,
     end: Frame,
 }
 
-impl Iterator for FrameIter {
+impl FrameRangeInclusive {
+    pub fn contains(&self, frame: &Frame) -> bool {
+        self.start <= *frame && *frame <= self.end
+    }
+
+    /// Like `FrameRange::step_by_frames`, `end` included
+    pub fn step_by_frames(&self, step: usize) -> FrameStepBy {
+        FrameStepBy::new(self.start.number, Some(self.end.number), step)
+    }
+}
+
+impl Iterator for FrameRangeInclusive {
     type Item = Frame;
 
     fn next(&mut self) -> Option<Frame> {
         if self.start <= self.end {
-            let frame = self.start.clone();
+            let frame = self.start;
             self.start.number += 1;
             Some(frame)
         } else {
             None
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = if self.start <= self.end { self.end.number - self.start.number + 1 } else { 0 };
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for FrameRangeInclusive {
+    fn next_back(&mut self) -> Option<Frame> {
+        if self.start <= self.end {
+            let frame = self.end;
+            // frame 0 can't be stepped below, so empty the range from the front instead
+            if self.end.number == 0 {
+                self.start.number += 1;
+            } else {
+                self.end.number -= 1;
+            }
+            Some(frame)
+        } else {
+            None
+        }
+    }
+}
+
+impl ExactSizeIterator for FrameRangeInclusive {}
+
+impl fmt::Debug for FrameRangeInclusive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}..={:?}", self.start, self.end)
+    }
+}
+
+/// Frame numbers and the addresses they cover
+impl fmt::Display for FrameRangeInclusive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frames {:#x}..={:#x} at {:#x}..{:#x}", self.start.number, self.end.number,
+               self.start.start_address(), self.end.end_address())
+    }
+}
+
+/// Half-open range of frames, `start..end`
+#[derive(Clone, PartialEq, Eq)]
+pub struct FrameRange {
+    pub start: Frame,
+    pub end: Frame,
 }
 
+impl FrameRange {
+    pub fn new(start: Frame, end: Frame) -> FrameRange {
+        FrameRange {
+            start: start,
+            end: end,
+        }
+    }
+
+    /// Frames containing an address in `start..=end`
+    pub fn containing(start: PhysicalAddress, end: PhysicalAddress) -> FrameRange {
+        FrameRange::new(Frame::containing_address(start), Frame::containing_address(end) + 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.number.saturating_sub(self.start.number)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, frame: &Frame) -> bool {
+        self.start <= *frame && *frame < self.end
+    }
+
+    /// Touching ranges don't overlap
+    pub fn overlaps(&self, other: &FrameRange) -> bool {
+        !self.is_empty() && !other.is_empty() && self.start < other.end && other.start < self.end
+    }
+
+    /// `None` if the ranges don't overlap
+    pub fn intersection(&self, other: &FrameRange) -> Option<FrameRange> {
+        if self.overlaps(other) {
+            Some(FrameRange::new(core::cmp::max(self.start, other.start),
+                                 core::cmp::min(self.end, other.end)))
+        } else {
+            None
+        }
+    }
+
+    /// Frames of `self` which are not in `other`, as the parts below and above `other`
+    pub fn difference(&self, other: &FrameRange) -> (Option<FrameRange>, Option<FrameRange>) {
+        if self.is_empty() {
+            (None, None)
+        } else if !self.overlaps(other) {
+            (Some(self.clone()), None)
+        } else {
+            let below = if self.start < other.start {
+                Some(FrameRange::new(self.start, other.start))
+            } else {
+                None
+            };
+            let above = if other.end < self.end {
+                Some(FrameRange::new(other.end, self.end))
+            } else {
+                None
+            };
+            (below, above)
+        }
+    }
+
+    /// Every `step`-th frame, starting at the first frame whose number is a
+    /// multiple of `step`, e.g. the first frame of every huge frame for 512
+    pub fn step_by_frames(&self, step: usize) -> FrameStepBy {
+        FrameStepBy::new(self.start.number, self.end.number.checked_sub(1), step)
+    }
+}
+
+impl Iterator for FrameRange {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.start < self.end {
+            let frame = self.start;
+            self.start.number += 1;
+            Some(frame)
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len(), Some(self.len()))
+    }
+}
+
+impl DoubleEndedIterator for FrameRange {
+    fn next_back(&mut self) -> Option<Frame> {
+        if self.start < self.end {
+            self.end.number -= 1;
+            Some(self.end)
+        } else {
+            None
+        }
+    }
+}
+
+impl ExactSizeIterator for FrameRange {}
+
+impl fmt::Debug for FrameRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}..{:?}", self.start, self.end)
+    }
+}
+
+/// Frame numbers and the addresses they cover
+impl fmt::Display for FrameRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frames {:#x}..{:#x} at {:#x}..{:#x}", self.start.number, self.end.number,
+               self.start.start_address(), self.end.start_address())
+    }
+}
+
+/// Frames of a range whose numbers are multiples of `step`, see `FrameRange::step_by_frames`
+#[derive(Debug)]
+pub struct FrameStepBy {
+    first: usize,
+    len: usize,
+    step: usize,
+}
+
+impl FrameStepBy {
+    /// Multiples of `step` in `start..=last`, none if there is no `last`
+    fn new(start: usize, last: Option<usize>, step: usize) -> FrameStepBy {
+        assert!(step > 0, "frames can't be stepped by 0");
+        let first = start.checked_add(step - 1).map(|number| number / step * step);
+        let len = match (first, last) {
+            (Some(first), Some(last)) if first <= last => (last - first) / step + 1,
+            _ => 0,
+        };
+        FrameStepBy {
+            first: first.unwrap_or(0),
+            len: len,
+            step: step,
+        }
+    }
+}
+
+impl Iterator for FrameStepBy {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None
+        }
+        let frame = Frame::from_number(self.first);
+        self.len -= 1;
+        // stepping past the last frame could overflow
+        if self.len > 0 {
+            self.first += self.step;
+        }
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl DoubleEndedIterator for FrameStepBy {
+    fn next_back(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None
+        }
+        self.len -= 1;
+        Some(Frame::from_number(self.first + self.len * self.step))
+    }
+}
+
+impl ExactSizeIterator for FrameStepBy {}
+
 pub fn enable_nxe_bit() {
     unsafe { 
         let mut flags = Efer::read();
@@ -155,25 +1063,19 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
     let elf_sections_tag = boot_info.elf_sections_tag().expect(
         "Elf sections tag required");
 
-    let kernel_start = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated()).map(|s| s.addr).min().unwrap();
-    let kernel_end = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated()).map(|s| s.addr + s.size).max()
-        .unwrap();
-
-    println!("kernel start: {:#x}, kernel end: {:#x}",
-             kernel_start,
-             kernel_end);
     println!("multiboot start: {:#x}, multiboot end: {:#x}",
              boot_info.start_address(),
              boot_info.end_address());
 
-    unsafe {frame_allocator_init(kernel_start as usize, kernel_end as usize, boot_info.start_address(), boot_info.end_address(), memory_map_tag.memory_areas());}
+    init_frame_allocator_from_elf_sections(elf_sections_tag.sections(),
+                                           PhysicalAddress::new(boot_info.start_address()),
+                                           PhysicalAddress::new(boot_info.end_address()),
+                                           memory_map_tag.memory_areas()).expect("frame allocator initialization failed");
 
     let mut active_table = paging::remap_the_kernel(boot_info);
 
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    let heap_start_page = Page::containing_address(VirtualAddress::new(HEAP_START));
+    let heap_end_page = Page::containing_address(VirtualAddress::new(HEAP_START + HEAP_SIZE - 1));
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
         let result = active_table.map(page, paging::EntryFlags::WRITABLE);
@@ -195,4 +1097,496 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
         stack_allocator: stack_allocator,
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn frame_at(address: usize) -> Frame {
+        Frame::containing_address(PhysicalAddress::from(address))
+    }
+
+    #[test]
+    fn address_alignment() {
+        assert_eq!((align_down(0x5abc, 1), align_up(0x5abc, 1)), (0x5abc, 0x5abc));
+        assert_eq!((align_down(0x5abc, PAGE_SIZE), align_up(0x5abc, PAGE_SIZE)), (0x5000, 0x6000));
+        assert_eq!((align_down(0x6000, PAGE_SIZE), align_up(0x6000, PAGE_SIZE)), (0x6000, 0x6000));
+        assert_eq!((align_down(0x345678, 0x200000), align_up(0x345678, 0x200000)), (0x200000, 0x400000));
+        assert!(is_aligned(0x5abc, 1) && is_aligned(0x6000, PAGE_SIZE) && is_aligned(0x400000, 0x200000));
+        assert!(!is_aligned(0x5abc, PAGE_SIZE) && !is_aligned(0x201000, 0x200000));
+    }
+
+    #[test]
+    fn align_up_saturates_instead_of_wrapping() {
+        let last_page = core::usize::MAX - (PAGE_SIZE - 1);
+        assert_eq!(align_up(core::usize::MAX, 1), core::usize::MAX);
+        assert_eq!(align_up(last_page, PAGE_SIZE), last_page);
+        assert_eq!(align_up(last_page + 1, PAGE_SIZE), last_page);
+        assert_eq!(align_up(core::usize::MAX, PAGE_SIZE), last_page);
+        assert_eq!(checked_align_up(last_page, PAGE_SIZE), Some(last_page));
+        assert_eq!(checked_align_up(last_page + 1, PAGE_SIZE), None);
+        assert_eq!(checked_align_up(core::usize::MAX, 0x200000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "alignment 3 is not a power of two")]
+    fn alignment_must_be_a_power_of_two() {
+        align_up(0x5abc, 3);
+    }
+
+    #[test]
+    fn physical_address_alignment() {
+        let address = PhysicalAddress::new(0x5abc);
+        assert_eq!(address.align_down(PAGE_SIZE), PhysicalAddress::new(0x5000));
+        assert_eq!(address.align_up(PAGE_SIZE), PhysicalAddress::new(0x6000));
+        assert_eq!(PhysicalAddress::new(0x6000).align_up(PAGE_SIZE).as_usize(), 0x6000);
+        assert_eq!(address.offset(0x544), PhysicalAddress::new(0x6000));
+        assert_eq!(address.checked_add(core::usize::MAX), None);
+        assert_eq!(format!("{}", address), "0x5abc");
+    }
+
+    #[test]
+    fn virtual_address_indices() {
+        let address = VirtualAddress::new(0x0000_7f12_3456_789a);
+        assert_eq!((address.p4_index(), address.p3_index(), address.p2_index(), address.p1_index()),
+                   (0o376, 0o110, 0o642, 0o547));
+        assert_eq!(address.page_offset(), 0x89a);
+
+        // recursive mapping of the P4 table
+        let address = VirtualAddress::new(0xffff_ffff_ffff_f008);
+        assert_eq!((address.p4_index(), address.p3_index(), address.p2_index(), address.p1_index()),
+                   (0o777, 0o777, 0o777, 0o777));
+        assert_eq!(address.page_offset(), 8);
+        assert_eq!(Page::containing_address(address).start_address(), VirtualAddress::new(0xffff_ffff_ffff_f000));
+    }
+
+    #[test]
+    fn higher_half_page_indices() {
+        let page = Page::containing_address(VirtualAddress::new(0xffff_8000_4020_3abc));
+        assert_eq!((page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()), (256, 1, 1, 3));
+        assert_eq!(page.start_address(), VirtualAddress::new(0xffff_8000_4020_3000));
+
+        let page = Page::containing_address(VirtualAddress::new(0xffff_ffff_8000_0000));
+        assert_eq!((page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()), (511, 510, 0, 0));
+    }
+
+    #[test]
+    fn page_ranges_cross_p2_boundaries() {
+        let start = Page::containing_address(VirtualAddress::new(0x3fe000));
+        let pages: Vec<Page> = Page::range(start, start + 4).collect();
+        assert_eq!(pages.iter().map(|page| page.start_address().as_usize()).collect::<Vec<_>>(),
+                   vec![0x3fe000, 0x3ff000, 0x400000, 0x401000]);
+        assert_eq!(pages.iter().map(|page| (page.p2_index(), page.p1_index())).collect::<Vec<_>>(),
+                   vec![(1, 510), (1, 511), (2, 0), (2, 1)]);
+        assert_eq!(Page::range_inclusive(start, start + 3).collect::<Vec<_>>(), pages);
+        assert_eq!(Page::range(start, start).count(), 0);
+    }
+
+    #[test]
+    fn page_arithmetic() {
+        let mut page = Page::from_number(5);
+        page += 3;
+        assert_eq!(page, Page::from_number(5) + 3);
+        assert_eq!(page - 10, Page::from_number(0));
+        assert_eq!(page - Page::from_number(5), 3);
+        assert_eq!(Page::from_frame_identity(Frame::from_number(0xb8)).start_address(), VirtualAddress::new(0xb8000));
+    }
+
+    #[test]
+    fn non_canonical_addresses_are_rejected() {
+        assert!(VirtualAddress::try_new(0x0000_7fff_ffff_ffff).is_some());
+        assert!(VirtualAddress::try_new(0xffff_8000_0000_0000).is_some());
+        assert!(VirtualAddress::try_new(0x0000_8000_0000_0000).is_none());
+        assert!(VirtualAddress::try_new(0xffff_7fff_ffff_ffff).is_none());
+    }
+
+    const VGA_FRAME: Frame = Frame::containing_address(PhysicalAddress::new(0xb8abc));
+
+    #[test]
+    fn frame_addresses() {
+        let frame = frame_at(0);
+        assert_eq!(frame.start_address(), 0);
+        assert_eq!(frame.end_address(), Frame::size());
+
+        let frame = frame_at(0x5abc);
+        assert_eq!(frame.start_address(), 0x5000);
+        assert_eq!(frame.end_address(), 0x6000);
+        assert_eq!(frame_at(frame.end_address() - 1), frame);
+        assert_eq!(frame_at(frame.end_address()).start_address(), 0x6000);
+        assert_eq!((VGA_FRAME.start_address(), VGA_FRAME.end_address()), (0xb8000, 0xb9000));
+
+        let frame = frame_at(0xffff_ffff);
+        assert_eq!(frame.start_address(), 0xffff_f000);
+        assert_eq!(frame.end_address(), 0x1_0000_0000);
+        assert_eq!(frame.end_address() - frame.start_address(), Frame::size());
+    }
+
+    #[test]
+    fn frame_addresses_round_trip() {
+        let frame = frame_at(0x5000);
+        assert_eq!(frame.start_address(), 0x5000);
+        assert_eq!(frame_at(frame.start_address()), frame);
+
+        let frame = frame_at(0x5abc);
+        assert_eq!(frame.start_address(), 0x5000);
+        assert_eq!(frame_at(frame.end_address() - 1), frame);
+        assert_eq!(frame_at(frame.end_address()), frame + 1);
+    }
+
+    #[test]
+    fn frames_from_raw_numbers() {
+        for &number in &[0, 0x5, 0x13e, MAX_PHYSICAL_ADDRESS / PAGE_SIZE - 1] {
+            assert_eq!(Frame::try_from(number).map(|frame| frame.number()), Ok(number));
+        }
+        assert_eq!(Frame::try_from(MAX_PHYSICAL_ADDRESS / PAGE_SIZE), Err(FrameAllocError::InvalidRange));
+        assert_eq!(PhysFrame::<Size2MiB>::try_from(MAX_PHYSICAL_ADDRESS / PAGE_SIZE / 512 - 1).unwrap().end_address(),
+                   MAX_PHYSICAL_ADDRESS);
+        assert!(PhysFrame::<Size2MiB>::try_from(MAX_PHYSICAL_ADDRESS / PAGE_SIZE / 512).is_err());
+    }
+
+    #[test]
+    fn non_zero_frames() {
+        assert_eq!(NonZeroFrame::new(Frame::from_number(0)), None);
+        assert_eq!(NonZeroFrame::try_from(Frame::from_number(0)), Err(FrameAllocError::Reserved));
+        let frame = NonZeroFrame::new(Frame::from_number(0x13e)).unwrap();
+        assert_eq!((frame.get(), frame.number()), (Frame::from_number(0x13e), 0x13e));
+        assert_eq!(Frame::from(frame), Frame::from_number(0x13e));
+        assert_eq!(NonZeroFrame::try_from(Frame::from_number(0x13e)), Ok(frame));
+        assert_eq!(format!("{:?}", frame), "Frame(0x13e, addr=0x13e000)");
+        assert_eq!(core::mem::size_of::<Option<NonZeroFrame>>(), core::mem::size_of::<Frame>());
+    }
+
+    #[test]
+    fn frame_pointers_through_offset_mapping() {
+        // fake physical memory of two frames, mapped at the start of the buffer
+        let mut memory = vec![0u8; 2 * PAGE_SIZE];
+        let physical_memory_offset = memory.as_mut_ptr() as usize;
+        let frame = Frame::from_number(1);
+        unsafe {
+            assert_eq!(frame.as_ptr_offset(physical_memory_offset) as usize, physical_memory_offset + PAGE_SIZE);
+            assert_eq!(frame.as_ptr_identity() as usize, PAGE_SIZE);
+            *frame.as_ptr_offset(physical_memory_offset) = 0xab;
+            frame.as_bytes_offset(physical_memory_offset)[PAGE_SIZE - 1] = 0xcd;
+        }
+        assert_eq!(memory[PAGE_SIZE], 0xab);
+        assert_eq!(memory[2 * PAGE_SIZE - 1], 0xcd);
+        assert_eq!(memory.iter().filter(|&&byte| byte != 0).count(), 2);
+    }
+
+    #[test]
+    fn frames_are_formatted_in_hex() {
+        let frame = Frame::from_number(0x13e);
+        assert_eq!(format!("{:?}", frame), "Frame(0x13e, addr=0x13e000)");
+        assert_eq!(format!("{}", frame), "frame 0x13e at 0x13e000");
+
+        let range = Frame::range(Frame::from_number(1), Frame::from_number(3));
+        assert_eq!(format!("{:?}", range), "Frame(0x1, addr=0x1000)..Frame(0x3, addr=0x3000)");
+        assert_eq!(format!("{}", range), "frames 0x1..0x3 at 0x1000..0x3000");
+        let range = Frame::range_inclusive(Frame::from_number(1), Frame::from_number(3));
+        assert_eq!(format!("{:?}", range), "Frame(0x1, addr=0x1000)..=Frame(0x3, addr=0x3000)");
+        assert_eq!(format!("{}", range), "frames 0x1..=0x3 at 0x1000..0x4000");
+    }
+
+    #[test]
+    fn checked_containing_address_rejects_huge_addresses() {
+        assert_eq!(Frame::checked_containing_address(0x5abc), Some(frame_at(0x5000)));
+        assert_eq!(Frame::checked_containing_address(MAX_PHYSICAL_ADDRESS), Some(frame_at(MAX_PHYSICAL_ADDRESS)));
+        assert_eq!(Frame::checked_containing_address(MAX_PHYSICAL_ADDRESS + 1), None);
+        assert_eq!(Frame::checked_containing_address(core::usize::MAX), None);
+    }
+
+    #[test]
+    fn containing_address_aligns_to_frame_size() {
+        let address = PhysicalAddress::new(0x4765_4321);
+        assert_eq!(Frame::containing_address(address).start_address(), 0x4765_4000);
+        assert_eq!(PhysFrame::<Size2MiB>::containing_address(address).start_address(), 0x4760_0000);
+        assert_eq!(PhysFrame::<Size1GiB>::containing_address(address).start_address(), 0x4000_0000);
+        assert_eq!(PhysFrame::<Size2MiB>::containing_address(address).end_address(), 0x4780_0000);
+        assert_eq!(PhysFrame::<Size1GiB>::size(), 0x4000_0000);
+
+        assert!(Frame::from_start_address(PhysicalAddress::new(0x4765_4000)).is_some());
+        assert!(PhysFrame::<Size2MiB>::from_start_address(PhysicalAddress::new(0x4765_4000)).is_none());
+        assert!(PhysFrame::<Size2MiB>::from_start_address(PhysicalAddress::new(0x4760_0000)).is_some());
+        assert!(PhysFrame::<Size1GiB>::from_start_address(PhysicalAddress::new(0x4760_0000)).is_none());
+    }
+
+    #[test]
+    fn huge_frame_split_and_promote_round_trip() {
+        let huge_frame = PhysFrame::<Size2MiB>::from_number(3);
+        let frames = huge_frame.split();
+        assert_eq!(frames, Frame::range(frame_at(0x60_0000), frame_at(0x80_0000)));
+        assert_eq!(PhysFrame::promote(&frames), Some(huge_frame));
+
+        assert_eq!(PhysFrame::promote(&Frame::range(frame_at(0x60_1000), frame_at(0x80_1000))), None);
+        assert_eq!(PhysFrame::promote(&Frame::range(frame_at(0x60_0000), frame_at(0x7f_f000))), None);
+    }
+
+    #[test]
+    fn huge_frame_range_splits_into_regular_frames() {
+        let frames = HugeFrame::split_range(HugeFrame::from_number(1), HugeFrame::from_number(3));
+        assert_eq!(frames.len(), 2 * 512);
+        assert_eq!(frames, Frame::range(frame_at(0x20_0000), frame_at(0x60_0000)));
+        let constituents = HugeFrame::from_number(1).split().collect::<Vec<_>>();
+        assert_eq!(constituents.len(), 512);
+        assert!(constituents.iter().enumerate().all(|(i, frame)| frame.number() == 512 + i));
+        assert!(HugeFrame::split_range(HugeFrame::from_number(3), HugeFrame::from_number(3)).is_empty());
+    }
+
+    #[test]
+    fn frame_arithmetic() {
+        assert_eq!(frame_at(0x5000) + 3, frame_at(0x8000));
+        assert_eq!(frame_at(0x5000) - 3, frame_at(0x2000));
+        assert_eq!(frame_at(0x2000) - 3, frame_at(0));
+        assert_eq!(frame_at(0x8000) - frame_at(0x5000), 3);
+
+        let mut frame = Frame::from_number(7);
+        frame += 2;
+        assert_eq!(frame, Frame::from_number(9));
+    }
+
+    #[test]
+    fn frames_sort_like_their_numbers() {
+        let numbers = vec![7, 0, 300, 2, 7, 1];
+        let mut frames = numbers.iter().map(|&number| Frame::from_number(number)).collect::<Vec<_>>();
+        frames.sort();
+        let mut numbers = numbers;
+        numbers.sort();
+        assert_eq!(frames.iter().map(|frame| frame.number()).collect::<Vec<_>>(), numbers);
+        assert_eq!(Frame::from_number(3).cmp(&Frame::from_number(3)), core::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn checked_frame_arithmetic() {
+        assert_eq!(Frame::from_number(5).checked_add(3), Some(Frame::from_number(8)));
+        assert_eq!(Frame::from_number(core::usize::MAX).checked_add(1), None);
+        assert_eq!(Frame::from_number(5).checked_sub(5), Some(Frame::from_number(0)));
+        assert_eq!(Frame::from_number(5).checked_sub(6), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame 6 comes after frame 5")]
+    fn frame_distance_must_not_be_negative() {
+        let _ = Frame::from_number(5) - Frame::from_number(6);
+    }
+
+    #[test]
+    fn from_number_matches_range_inclusive() {
+        let range = Frame::range_inclusive(Frame::from_number(3), Frame::from_number(6));
+        assert!(range.eq((3..7).map(Frame::from_number)));
+    }
+
+    #[test]
+    fn frame_range_iterates_both_ways() {
+        let range = Frame::range_inclusive(Frame::from_number(3), Frame::from_number(6));
+        assert_eq!(range.map(|frame| frame.number()).collect::<Vec<_>>(), vec![3, 4, 5, 6]);
+        let range = Frame::range_inclusive(Frame::from_number(3), Frame::from_number(6));
+        assert_eq!(range.rev().map(|frame| frame.number()).collect::<Vec<_>>(), vec![6, 5, 4, 3]);
+
+        let mut range = Frame::range_inclusive(Frame::from_number(0), Frame::from_number(1));
+        assert_eq!(range.next_back(), Some(Frame::from_number(1)));
+        assert_eq!(range.next_back(), Some(Frame::from_number(0)));
+        assert_eq!(range.next_back(), None);
+        assert_eq!(range.next(), None);
+    }
+
+    #[test]
+    fn frame_range_len_and_contains() {
+        let mut range = Frame::range_inclusive(Frame::from_number(3), Frame::from_number(6));
+        assert_eq!(range.len(), 4);
+        assert!(range.contains(&Frame::from_number(3)) && range.contains(&Frame::from_number(6)));
+        assert!(!range.contains(&Frame::from_number(2)) && !range.contains(&Frame::from_number(7)));
+        range.next();
+        range.next_back();
+        assert_eq!(range.len(), 2);
+        assert!(!range.contains(&Frame::from_number(3)));
+
+        let single = Frame::range_inclusive(Frame::from_number(5), Frame::from_number(5));
+        assert_eq!(single.len(), 1);
+        assert!(single.contains(&Frame::from_number(5)));
+        let mut single = single;
+        single.next();
+        assert_eq!(single.len(), 0);
+        assert!(!single.contains(&Frame::from_number(5)));
+    }
+
+    fn frames(start: usize, end: usize) -> FrameRange {
+        FrameRange::new(Frame::from_number(start), Frame::from_number(end))
+    }
+
+    #[test]
+    fn frame_range_basics() {
+        let range = FrameRange::containing(PhysicalAddress::new(0x3abc), PhysicalAddress::new(0x6000));
+        assert_eq!(range, frames(3, 7));
+        assert_eq!(range.len(), 4);
+        assert!(range.contains(&Frame::from_number(3)) && range.contains(&Frame::from_number(6)));
+        assert!(!range.contains(&Frame::from_number(7)));
+        assert_eq!(range.rev().map(|frame| frame.number()).collect::<Vec<_>>(), vec![6, 5, 4, 3]);
+
+        let empty = frames(5, 5);
+        assert!(empty.is_empty() && empty.len() == 0);
+        assert_eq!(frames(6, 5).len(), 0);
+        assert!(frames(0, 0).next().is_none());
+    }
+
+    #[test]
+    fn empty_half_open_range_yields_nothing() {
+        assert_eq!(Frame::range(Frame::from_number(4), Frame::from_number(4)).count(), 0);
+        assert_eq!(Frame::range(Frame::from_number(0), Frame::from_number(0)).next_back(), None);
+        assert_eq!(Frame::range(Frame::from_number(4), Frame::from_number(4)).len(), 0);
+    }
+
+    #[test]
+    fn reverse_iteration_matches_forward() {
+        let mut forward = Frame::range(Frame::from_number(0), Frame::from_number(5)).collect::<Vec<_>>();
+        forward.reverse();
+        assert_eq!(Frame::range(Frame::from_number(0), Frame::from_number(5)).rev().collect::<Vec<_>>(), forward);
+
+        let mut forward = Frame::range_inclusive(Frame::from_number(0), Frame::from_number(4)).collect::<Vec<_>>();
+        forward.reverse();
+        assert_eq!(Frame::range_inclusive(Frame::from_number(0), Frame::from_number(4)).rev().collect::<Vec<_>>(), forward);
+    }
+
+    fn numbers<I: Iterator<Item = Frame>>(frames: I) -> Vec<usize> {
+        frames.map(|frame| frame.number()).collect()
+    }
+
+    #[test]
+    fn stepping_starts_at_an_aligned_frame() {
+        assert_eq!(numbers(frames(3, 20).step_by_frames(4)), vec![4, 8, 12, 16]);
+        assert_eq!(numbers(frames(4, 20).step_by_frames(4)), vec![4, 8, 12, 16]);
+        assert_eq!(numbers(Frame::range_inclusive(Frame::from_number(3), Frame::from_number(20)).step_by_frames(4)),
+                   vec![4, 8, 12, 16, 20]);
+        assert_eq!(numbers(frames(5, 20).step_by_frames(1)), numbers(frames(5, 20)));
+        assert_eq!(numbers(frames(0, 2048).step_by_frames(512)), vec![0, 512, 1024, 1536]);
+
+        assert_eq!(frames(1, 4).step_by_frames(4).count(), 0);
+        assert_eq!(frames(0, 0).step_by_frames(4).next_back(), None);
+        assert_eq!(frames(8, 4).step_by_frames(4).len(), 0);
+        assert_eq!(Frame::range_inclusive(Frame::from_number(5), Frame::from_number(4)).step_by_frames(1).len(), 0);
+        let near_end = core::usize::MAX - 2;
+        assert_eq!(frames(near_end, core::usize::MAX).step_by_frames(8).len(), 0);
+        assert_eq!(numbers(Frame::range_inclusive(Frame::from_number(near_end), Frame::from_number(core::usize::MAX))
+                               .step_by_frames(1)),
+                   vec![near_end, near_end + 1, core::usize::MAX]);
+    }
+
+    #[test]
+    fn stepping_iterates_both_ways() {
+        let forward = numbers(frames(3, 40).step_by_frames(8));
+        let mut backward = numbers(frames(3, 40).step_by_frames(8).rev());
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        // len stays exact while frames are taken from both ends
+        let mut steps = frames(3, 40).step_by_frames(8);
+        let mut taken = Vec::new();
+        let mut from_front = true;
+        while steps.len() > 0 {
+            let len = steps.len();
+            taken.push(if from_front { steps.next() } else { steps.next_back() }.unwrap().number());
+            assert_eq!(steps.len(), len - 1);
+            assert_eq!(steps.size_hint(), (len - 1, Some(len - 1)));
+            from_front = !from_front;
+        }
+        assert_eq!((steps.next(), steps.next_back()), (None, None));
+        taken.sort();
+        assert_eq!(taken, forward);
+    }
+
+    #[test]
+    fn len_is_exact_after_reverse_consumption() {
+        let mut range = frames(3, 10);
+        range.next_back();
+        range.next();
+        assert_eq!((range.len(), range.size_hint()), (5, (5, Some(5))));
+        assert_eq!(numbers(range.rev()), vec![8, 7, 6, 5, 4]);
+
+        let mut range = Frame::range_inclusive(Frame::from_number(3), Frame::from_number(10));
+        range.next_back();
+        range.next_back();
+        assert_eq!((range.len(), range.size_hint()), (6, (6, Some(6))));
+        assert_eq!(numbers(range), vec![3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn disjoint_frame_ranges() {
+        let (a, b) = (frames(0, 4), frames(8, 12));
+        assert!(!a.overlaps(&b) && !b.overlaps(&a));
+        assert_eq!(a.intersection(&b), None);
+        assert_eq!(a.difference(&b), (Some(frames(0, 4)), None));
+    }
+
+    #[test]
+    fn touching_frame_ranges() {
+        let (a, b) = (frames(0, 4), frames(4, 8));
+        assert!(!a.overlaps(&b));
+        assert_eq!(a.intersection(&b), None);
+        assert_eq!(b.difference(&a), (Some(frames(4, 8)), None));
+    }
+
+    #[test]
+    fn overlapping_frame_ranges() {
+        let (a, b) = (frames(0, 6), frames(4, 8));
+        assert!(a.overlaps(&b) && b.overlaps(&a));
+        assert_eq!(a.intersection(&b), Some(frames(4, 6)));
+        assert_eq!(a.difference(&b), (Some(frames(0, 4)), None));
+        assert_eq!(b.difference(&a), (None, Some(frames(6, 8))));
+    }
+
+    #[test]
+    fn nested_frame_ranges() {
+        let (outer, inner) = (frames(0, 10), frames(3, 5));
+        assert_eq!(outer.intersection(&inner), Some(frames(3, 5)));
+        assert_eq!(inner.intersection(&outer), Some(frames(3, 5)));
+        assert_eq!(outer.difference(&inner), (Some(frames(0, 3)), Some(frames(5, 10))));
+        assert_eq!(inner.difference(&outer), (None, None));
+    }
+
+    #[test]
+    fn identical_frame_ranges() {
+        let range = frames(2, 6);
+        assert!(range.overlaps(&frames(2, 6)));
+        assert_eq!(range.intersection(&frames(2, 6)), Some(frames(2, 6)));
+        assert_eq!(range.difference(&frames(2, 6)), (None, None));
+        assert!(!frames(2, 2).overlaps(&frames(2, 2)));
+    }
+
+    // Every test of the global allocator lives here, as tests run in parallel
+    #[test]
+    fn global_frame_allocator() {
+        reset_frame_allocator();
+        assert_eq!(try_allocate_frame(), Err(FrameAllocError::Uninitialized));
+
+        let bitmap = Box::leak(Box::new([0; 4]));
+        let reserved = Box::leak(Box::new([0; 4]));
+        let zero = PhysicalAddress::new(0);
+        let allocator = BitmapFrameAllocator::from_areas(bitmap, reserved, zero, zero, zero, zero,
+                                                         vec![(0, 16 * PAGE_SIZE)].into_iter()).unwrap();
+        // a failed initialization can be retried
+        assert_eq!(install_new_frame_allocator(|| Err(FrameAllocError::OutOfMemory)), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(try_allocate_frame(), Err(FrameAllocError::Uninitialized));
+        assert_eq!(install_new_frame_allocator(|| Ok(allocator)), Ok(()));
+        assert_eq!(claim_frame_allocator(), Err(FrameAllocError::AlreadyInitialized));
+
+        let frame = try_allocate_frame().unwrap();
+        assert_eq!(frame.number(), 1);
+        deallocate_frame(frame);
+        assert_eq!(allocate_frame().unwrap().number(), 1);
+        assert_eq!(frame_allocator().as_ref().unwrap().used_frame_count(), 2);
+
+        // quotas can wrap the kernel's allocator through the global handle
+        let mut quota = QuotaAllocator::new(GlobalFrameAllocator, 1);
+        let frame = quota.try_allocate_frame().unwrap();
+        assert_eq!(frame.number(), 2);
+        assert_eq!(quota.try_allocate_frame(), Err(FrameAllocError::QuotaExceeded));
+        quota.deallocate_frame(frame);
+        assert!(!frame_allocator().as_ref().unwrap().frame_is_used(2));
+
+        reset_frame_allocator();
+        assert_eq!(GlobalFrameAllocator.allocate_contiguous_frames(1), Err(FrameAllocError::Uninitialized));
+        assert_eq!(try_allocate_frame(), Err(FrameAllocError::Uninitialized));
+    }
+}
@@ -1,4 +1,5 @@
 use memory::Frame;
+use super::PhysicalAddress;
 use multiboot2::ElfSection;
 use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
 
@@ -31,7 +32,7 @@ impl Entry {
     /// Get the associated frame, if available
     pub fn pointed_frame(&self) -> Option<Frame> {
         if self.flags().contains(EntryFlags::PRESENT) {
-            Some(Frame::containing_address(self.0 as usize & ADDRESS_MASK))
+            Some(Frame::containing_address(PhysicalAddress::new(self.0 as usize & ADDRESS_MASK)))
         } else {
             None
         }
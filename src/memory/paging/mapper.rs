@@ -96,9 +96,9 @@ impl Mapper {
     }
 
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
-        let offset = virtual_address % PAGE_SIZE;
+        let offset = virtual_address.page_offset();
         self.translate_page(Page::containing_address(virtual_address))
-            .map(|frame| frame.number * PAGE_SIZE + offset)
+            .map(|frame| PhysicalAddress::new(frame.number * PAGE_SIZE + offset))
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
@@ -127,7 +127,7 @@ impl Mapper {
     }
 
     pub fn identity_map(&mut self, frame: Frame, flags: EntryFlags) -> MapperFlush {
-        let page = Page::containing_address(frame.start_address());
+        let page = Page::from_frame_identity(frame);
         self.map_to(page, frame, flags)
     }
 
@@ -142,7 +142,7 @@ impl Mapper {
                     frame = if let Some(frame) = p1[page.p1_index()].pointed_frame() {
                         frame
                     } else {
-                        panic!("unmap_inner({:X}): frame not found", page.start_address())
+                        panic!("unmap_inner({}): frame not found", page.start_address())
                     };
 
                     p1.decrement_entry_count();
@@ -152,7 +152,7 @@ impl Mapper {
                         return frame;
                     }
                 } else {
-                    panic!("unmap_inner({:X}): p1 not found", page.start_address());
+                    panic!("unmap_inner({}): p1 not found", page.start_address());
                 }
 
                 if let Some(p1_frame) = p2[page.p2_index()].pointed_frame() {
@@ -161,14 +161,14 @@ impl Mapper {
                     p2[page.p2_index()].set_unused();
                     deallocate_frame(p1_frame);
                 } else {
-                    panic!("unmap_inner({:X}): p1_frame not found", page.start_address());
+                    panic!("unmap_inner({}): p1_frame not found", page.start_address());
                 }
 
                 if ! p2.is_unused() {
                     return frame;
                 }
             } else {
-                panic!("unmap_inner({:X}): p2 not found", page.start_address());
+                panic!("unmap_inner({}): p2 not found", page.start_address());
             }
 
             if let Some(p2_frame) = p3[page.p3_index()].pointed_frame() {
@@ -177,14 +177,14 @@ impl Mapper {
                 p3[page.p3_index()].set_unused();
                 deallocate_frame(p2_frame);
             } else {
-                panic!("unmap_inner({:X}): p2_frame not found", page.start_address());
+                panic!("unmap_inner({}): p2_frame not found", page.start_address());
             }
 
             if ! p3.is_unused() {
                 return frame;
             }
         } else {
-            panic!("unmap_inner({:X}): p3 not found", page.start_address());
+            panic!("unmap_inner({}): p3 not found", page.start_address());
         }
 
         if let Some(p3_frame) = p4[page.p4_index()].pointed_frame() {
@@ -193,7 +193,7 @@ impl Mapper {
             p4[page.p4_index()].set_unused();
             deallocate_frame(p3_frame);
         } else {
-            panic!("unmap_inner({:X}): p3_frame not found", page.start_address());
+            panic!("unmap_inner({}): p3_frame not found", page.start_address());
         }
 
         frame
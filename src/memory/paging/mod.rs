@@ -3,6 +3,7 @@ mod table;
 mod temporary_page;
 mod mapper;
 
+use memory;
 use memory::Frame;
 use memory::allocate_frame;
 
@@ -15,82 +16,115 @@ use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::PhysFrame;
 
 use self::mapper::Mapper;
-use core::ops::{Deref, DerefMut, Add};
+use core::ops::{Deref, DerefMut};
+use core::fmt;
 
-pub type PhysicalAddress = usize;
-pub type VirtualAddress = usize;
+/// Address in physical memory, kept apart from virtual addresses and frame numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysicalAddress(usize);
 
-pub const PAGE_SIZE: usize = 4096;
-const ENTRY_COUNT: usize = 512;
-
-use self::temporary_page::TemporaryPage;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Page {
-   number: usize,
-}
-
-impl Page {
-    pub fn range_inclusive(start: Page, end: Page) -> PageIter {
-        PageIter {
-            start: start,
-            end: end,
-        }
+impl PhysicalAddress {
+    pub const fn new(address: usize) -> PhysicalAddress {
+        PhysicalAddress(address)
     }
 
-    pub fn containing_address(address: VirtualAddress) -> Page {
-        assert!(address < 0x0000_8000_0000_0000 ||
-                address >= 0xffff_8000_0000_0000,
-                "invalid address: 0x{:x}", address);
-        Page { number: address / PAGE_SIZE }
+    pub const fn as_usize(&self) -> usize {
+        self.0
     }
 
-    pub fn start_address(&self) -> usize {
-        self.number * PAGE_SIZE
+    /// `align` must be a power of two
+    pub fn align_down(&self, align: usize) -> PhysicalAddress {
+        PhysicalAddress(memory::align_down(self.0, align))
     }
 
-    fn p4_index(&self) -> usize {
-        (self.number >> 27) & 0o777
+    /// `align` must be a power of two
+    pub fn align_up(&self, align: usize) -> PhysicalAddress {
+        PhysicalAddress(memory::align_up(self.0, align))
     }
-    fn p3_index(&self) -> usize {
-        (self.number >> 18) & 0o777
-    }
-    fn p2_index(&self) -> usize {
-        (self.number >> 9) & 0o777
+
+    /// The address `bytes` further on
+    pub fn offset(&self, bytes: usize) -> PhysicalAddress {
+        PhysicalAddress(self.0 + bytes)
     }
-    fn p1_index(&self) -> usize {
-        (self.number >> 0) & 0o777
+
+    pub fn checked_add(&self, bytes: usize) -> Option<PhysicalAddress> {
+        self.0.checked_add(bytes).map(PhysicalAddress)
     }
 }
 
-impl Add<usize> for Page {
-    type Output = Page;
-
-    fn add(self, rhs: usize) -> Page {
-        Page { number: self.number + rhs }
+impl From<usize> for PhysicalAddress {
+    fn from(address: usize) -> PhysicalAddress {
+        PhysicalAddress(address)
     }
 }
 
-#[derive(Clone)]
-pub struct PageIter {
-    start: Page,
-    end: Page,
+impl fmt::Display for PhysicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
 }
 
-impl Iterator for PageIter {
-    type Item = Page;
+/// Canonical x86_64 virtual address, i.e. bits 48 to 63 are copies of bit 47
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualAddress(usize);
+
+impl VirtualAddress {
+    /// Panics if the address is not canonical
+    pub fn new(address: usize) -> VirtualAddress {
+        VirtualAddress::try_new(address).unwrap_or_else(|| panic!("invalid address: 0x{:x}", address))
+    }
 
-    fn next(&mut self) -> Option<Page> {
-        if self.start <= self.end {
-            let page = self.start;
-            self.start.number += 1;
-            Some(page)
+    /// Returns `None` if the address is not canonical
+    pub fn try_new(address: usize) -> Option<VirtualAddress> {
+        if VirtualAddress::is_canonical(address) {
+            Some(VirtualAddress(address))
         } else {
             None
         }
     }
+
+    pub fn is_canonical(address: usize) -> bool {
+        address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+
+    pub fn p4_index(&self) -> usize {
+        (self.0 >> 39) & 0o777
+    }
+
+    pub fn p3_index(&self) -> usize {
+        (self.0 >> 30) & 0o777
+    }
+
+    pub fn p2_index(&self) -> usize {
+        (self.0 >> 21) & 0o777
+    }
+
+    pub fn p1_index(&self) -> usize {
+        (self.0 >> 12) & 0o777
+    }
+
+    /// Offset of the address in its page
+    pub fn page_offset(&self) -> usize {
+        self.0 % PAGE_SIZE
+    }
 }
 
+impl fmt::Display for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+pub const PAGE_SIZE: usize = 4096;
+const ENTRY_COUNT: usize = 512;
+
+use self::temporary_page::TemporaryPage;
+pub use memory::{Page, PageIter, PageRange};
+
 pub struct ActivePageTable {
     mapper: Mapper,
 }
@@ -123,13 +157,13 @@ impl ActivePageTable {
         where F: FnOnce(&mut Mapper)
     {
         {
-            let backup = Frame::containing_address(Cr3::read().0.start_address().as_u64() as usize);
+            let backup = Frame::containing_address(PhysicalAddress::new(Cr3::read().0.start_address().as_u64() as usize));
 
             // map temporary_page to current p4 table
-            let p4_table = temporary_page.map_table_frame(backup.clone(), self);
+            let p4_table = temporary_page.map_table_frame(backup, self);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            self.p4_mut()[511].set(table.p4_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.flush_all();
 
             // execute f in the new context
@@ -146,8 +180,8 @@ impl ActivePageTable {
 
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(
-                Cr3::read().0.start_address().as_u64() as usize
+            p4_frame: Frame::containing_address(PhysicalAddress::new(
+                Cr3::read().0.start_address().as_u64() as usize)
             ),
         };
         unsafe {
@@ -157,7 +191,7 @@ impl ActivePageTable {
     }
 
     pub fn flush(&mut self, page: Page) {
-        tlb::flush(x86_64::VirtAddr::new(page.start_address() as u64));
+        tlb::flush(x86_64::VirtAddr::new(page.start_address().as_usize() as u64));
     }
 
     pub fn flush_all(&mut self) {
@@ -176,11 +210,11 @@ impl InactivePageTable {
                temporary_page: &mut TemporaryPage)
                -> InactivePageTable {
         {
-            let table = temporary_page.map_table_frame(frame.clone(), active_table);
+            let table = temporary_page.map_table_frame(frame, active_table);
             // now we are able to zero the table
             table.zero();
             // set up recursive mapping for the table
-            table[511].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            table[511].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
         }
         temporary_page.unmap(active_table);
 
@@ -213,25 +247,25 @@ pub fn remap_the_kernel(boot_info: &BootInformation) -> ActivePageTable {
 
             let flags = EntryFlags::from_elf_section_flags(section);
 
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
+            let start_frame = Frame::containing_address(PhysicalAddress::new(section.start_address()));
+            let end_frame = Frame::containing_address(PhysicalAddress::new(section.end_address()).align_up(PAGE_SIZE));
 
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
+            for frame in Frame::range(start_frame, end_frame) {
                 let result = mapper.identity_map(frame, flags);
                 // The flush can be ignored as this is not the active table. See later active_table.switch
                 unsafe {result.ignore();}
             }
         }
         // identity map the VGA text buffer
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
+        let vga_buffer_frame = Frame::containing_address(PhysicalAddress::new(0xb8000));
         let result = mapper.identity_map(vga_buffer_frame, EntryFlags::WRITABLE);
         // The flush can be ignored as this is not the active table. See later active_table.switch
         unsafe {result.ignore();}
 
         // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
+        let multiboot_start = Frame::containing_address(PhysicalAddress::new(boot_info.start_address()));
+        let multiboot_end = Frame::containing_address(PhysicalAddress::new(boot_info.end_address()).align_up(PAGE_SIZE));
+        for frame in Frame::range(multiboot_start, multiboot_end) {
             let result = mapper.identity_map(frame, EntryFlags::PRESENT);
             // The flush can be ignored as this is not the active table. See later active_table.switch
             unsafe {result.ignore();}
@@ -242,9 +276,9 @@ pub fn remap_the_kernel(boot_info: &BootInformation) -> ActivePageTable {
     println!("NEW TABLE!!!");
 
     // turn the old p4 page into a guard page
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
+    let old_p4_page = Page::from_frame_identity(old_table.p4_frame);
     let result = active_table.unmap(old_p4_page);
     result.flush(&mut active_table);
-    println!("guard page at {:#x}", old_p4_page.start_address());
+    println!("guard page at {}", old_p4_page.start_address());
     active_table
 }
@@ -37,7 +37,7 @@ impl TemporaryPage {
     /// Maps the temporary page to the given page table frame in the active
     /// table. Returns a reference to the now mapped table.
     pub fn map_table_frame(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> &mut Table<Level1> {
-        unsafe { &mut *(self.map(frame, active_table) as *mut Table<Level1>) }
+        unsafe { &mut *(self.map(frame, active_table).as_usize() as *mut Table<Level1>) }
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+/// Wraps an allocator and limits how many frames may be allocated through
+/// it at the same time. Several quotas can share one allocator, e.g. the
+/// kernel's through `GlobalFrameAllocator` or another one through
+/// `&LockedFrameAllocator`.
+pub struct QuotaAllocator<A> {
+    allocator: A,
+    limit: usize,
+    used: usize,
+}
+
+impl<A: FrameAllocator> QuotaAllocator<A> {
+    pub fn new(allocator: A, limit: usize) -> QuotaAllocator<A> {
+        QuotaAllocator {
+            allocator: allocator,
+            limit: limit,
+            used: 0,
+        }
+    }
+
+    /// Number of frames allocated through this quota which are not freed yet
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Number of frames which can still be allocated before the limit is hit
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.used)
+    }
+
+    /// A limit below the current usage only stops new allocations,
+    /// frames which are already allocated are kept
+    pub fn set_limit(&mut self, frames: usize) {
+        self.limit = frames;
+    }
+}
+
+impl<A: FrameAllocator> FrameAllocator for QuotaAllocator<A> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.try_allocate_frame().ok()
+    }
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        if self.remaining() == 0 {
+            return Err(FrameAllocError::QuotaExceeded);
+        }
+        let frame = self.allocator.try_allocate_frame()?;
+        self.used += 1;
+        Ok(frame)
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        if count > self.remaining() {
+            return Err(FrameAllocError::QuotaExceeded);
+        }
+        let frame = self.allocator.allocate_contiguous_frames(count)?;
+        self.used += count;
+        Ok(frame)
+    }
+}
+
+/// The quota does not remember which frames it handed out, any frame freed
+/// through it is credited to it. Quotas sharing an allocator have to free
+/// their frames through the quota they allocated them from.
+impl<A: FrameAllocator + FrameDeallocator> FrameDeallocator for QuotaAllocator<A> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(self.used > 0, "frame {} was not allocated through this quota", frame.number());
+        self.used -= 1;
+        self.allocator.deallocate_frame(frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::locked_allocator;
+
+    #[test]
+    fn quota_limits_allocations() {
+        let mut quota = QuotaAllocator::new(locked_allocator(16), 2);
+        let frame = quota.allocate_frame().unwrap();
+        quota.allocate_frame().unwrap();
+        assert_eq!(quota.remaining(), 0);
+        assert_eq!(quota.try_allocate_frame(), Err(FrameAllocError::QuotaExceeded));
+        assert!(quota.allocate_frame().is_none());
+
+        quota.deallocate_frame(frame);
+        assert_eq!(quota.remaining(), 1);
+        assert_eq!(quota.allocate_contiguous_frames(2), Err(FrameAllocError::QuotaExceeded));
+        assert!(quota.allocate_frame().is_some());
+    }
+
+    #[test]
+    fn quotas_share_an_allocator() {
+        let allocator = locked_allocator(8);
+        let mut cache = QuotaAllocator::new(allocator, 4);
+        let mut other = QuotaAllocator::new(allocator, 4);
+
+        assert_eq!(cache.allocate_contiguous_frames(4), Ok(Frame::from_number(1)));
+        assert_eq!(other.try_allocate_frame().unwrap().number(), 5);
+        assert_eq!(other.try_allocate_frame().unwrap().number(), 6);
+        assert_eq!(other.try_allocate_frame().unwrap().number(), 7);
+        // the allocator runs out before the quota does
+        assert_eq!(other.try_allocate_frame(), Err(FrameAllocError::OutOfMemory));
+        assert_eq!(other.used(), 3);
+        assert_eq!(allocator.free_frame_count(), 0);
+    }
+
+    #[test]
+    fn shrinking_limit_below_usage() {
+        let mut quota = QuotaAllocator::new(locked_allocator(16), 4);
+        let first = quota.allocate_frame().unwrap();
+        let second = quota.allocate_frame().unwrap();
+        quota.allocate_frame().unwrap();
+
+        quota.set_limit(1);
+        assert_eq!(quota.remaining(), 0);
+        assert_eq!(quota.try_allocate_frame(), Err(FrameAllocError::QuotaExceeded));
+
+        quota.deallocate_frame(first);
+        quota.deallocate_frame(second);
+        assert_eq!(quota.used(), 1);
+        assert_eq!(quota.try_allocate_frame(), Err(FrameAllocError::QuotaExceeded));
+    }
+}
@@ -0,0 +1,203 @@
+use core;
+
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+/// Reference count of every frame, one `u16` per frame
+pub struct FrameRefCounter<'a> {
+    counts: &'a mut [u16],
+}
+
+impl<'a> FrameRefCounter<'a> {
+    /// `counts` needs one entry for every frame which can be referenced,
+    /// i.e. as many as the frame allocator manages
+    pub fn new(counts: &'a mut [u16]) -> FrameRefCounter<'a> {
+        for count in counts.iter_mut() {
+            *count = 0;
+        }
+        FrameRefCounter { counts: counts }
+    }
+
+    /// Does the table have an entry for the frame?
+    pub fn covers(&self, frame: &Frame) -> bool {
+        frame.number() < self.counts.len()
+    }
+
+    pub fn count(&self, frame: &Frame) -> u16 {
+        self.counts.get(frame.number()).cloned().unwrap_or(0)
+    }
+
+    pub fn incref(&mut self, frame: &Frame) {
+        let count = self.count_mut(frame);
+        assert!(*count < core::u16::MAX, "reference count of frame {} overflows", frame.number());
+        *count += 1;
+    }
+
+    /// Returns true if this was the last reference, so the frame can be freed
+    pub fn decref(&mut self, frame: &Frame) -> bool {
+        let count = self.count_mut(frame);
+        assert!(*count > 0, "frame {} is not referenced", frame.number());
+        *count -= 1;
+        *count == 0
+    }
+
+    fn count_mut(&mut self, frame: &Frame) -> &mut u16 {
+        assert!(frame.number() < self.counts.len(), "frame {} has no reference count", frame.number());
+        &mut self.counts[frame.number()]
+    }
+}
+
+/// Wraps an allocator so that frames can be shared, e.g. copy-on-write frames
+/// after a fork, and are only freed once every reference is dropped.
+/// Allocated frames start with one reference.
+pub struct RefCountedAllocator<'a, A> {
+    allocator: A,
+    counter: FrameRefCounter<'a>,
+}
+
+impl<'a, A: FrameAllocator> RefCountedAllocator<'a, A> {
+    pub fn new(allocator: A, counter: FrameRefCounter<'a>) -> RefCountedAllocator<'a, A> {
+        RefCountedAllocator {
+            allocator: allocator,
+            counter: counter,
+        }
+    }
+
+    /// Adds a reference to an allocated frame, e.g. when mapping it a second time
+    pub fn share(&mut self, frame: &Frame) {
+        assert!(self.counter.count(frame) > 0, "frame {} is not allocated", frame.number());
+        self.counter.incref(frame);
+    }
+
+    pub fn ref_count(&self, frame: &Frame) -> u16 {
+        self.counter.count(frame)
+    }
+}
+
+/// Frames the counter has no entry for are given back to the inner allocator
+impl<'a, A: FrameAllocator + FrameDeallocator> FrameAllocator for RefCountedAllocator<'a, A> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        let frame = self.allocator.allocate_frame()?;
+        if !self.counter.covers(&frame) {
+            self.allocator.deallocate_frame(frame);
+            return None
+        }
+        self.counter.incref(&frame);
+        Some(frame)
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        let frame = self.allocator.allocate_contiguous_frames(count)?;
+        if count > 0 && !self.counter.covers(&Frame::from_number(frame.number() + count - 1)) {
+            self.allocator.deallocate_contiguous_frames(frame, count);
+            return Err(FrameAllocError::InvalidRange)
+        }
+        for frame_number in frame.number()..frame.number() + count {
+            self.counter.incref(&Frame::from_number(frame_number));
+        }
+        Ok(frame)
+    }
+}
+
+/// Drops a reference, freeing the frame in the inner allocator if it was the last one
+impl<'a, A: FrameDeallocator> FrameDeallocator for RefCountedAllocator<'a, A> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if self.counter.decref(&frame) {
+            self.allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::MockAllocator;
+    use memory::bitmap_frame_allocator::BitmapFrameAllocator;
+    use memory::paging::{PAGE_SIZE, PhysicalAddress};
+
+    #[test]
+    fn incref_and_decref() {
+        let mut counts = vec![7; 4];
+        let mut counter = FrameRefCounter::new(&mut counts);
+        let frame = Frame::from_number(2);
+
+        assert_eq!(counter.count(&frame), 0);
+        counter.incref(&frame);
+        counter.incref(&frame);
+        assert!(!counter.decref(&frame));
+        assert!(counter.decref(&frame));
+        assert_eq!(counter.count(&frame), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame 1 is not referenced")]
+    fn decref_below_zero_panics() {
+        let mut counts = vec![0; 4];
+        let mut counter = FrameRefCounter::new(&mut counts);
+        counter.decref(&Frame::from_number(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "reference count of frame 0 overflows")]
+    fn incref_overflow_panics() {
+        let mut counts = vec![0; 4];
+        let mut counter = FrameRefCounter::new(&mut counts);
+        for _ in 0..core::u16::MAX as usize + 1 {
+            counter.incref(&Frame::from_number(0));
+        }
+    }
+
+    #[test]
+    fn shared_frame_is_freed_by_last_reference() {
+        let mut counts = vec![0; 4];
+        let mut allocator = RefCountedAllocator::new(MockAllocator::new(4),
+                                                     FrameRefCounter::new(&mut counts));
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.ref_count(&frame), 1);
+        allocator.share(&frame);
+        assert_eq!(allocator.ref_count(&frame), 2);
+
+        allocator.deallocate_frame(Frame::from_number(frame.number()));
+        assert!(allocator.allocator.used[frame.number()]);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), 1);
+
+        allocator.deallocate_frame(Frame::from_number(frame.number()));
+        assert!(!allocator.allocator.used[frame.number()]);
+        assert_eq!(allocator.allocate_frame().unwrap().number(), frame.number());
+        assert_eq!(allocator.ref_count(&frame), 1);
+    }
+
+    #[test]
+    fn frames_without_reference_count_are_given_back() {
+        let mut counts = vec![0; 2];
+        let mut allocator = RefCountedAllocator::new(MockAllocator::new(6),
+                                                     FrameRefCounter::new(&mut counts));
+
+        assert_eq!(allocator.allocate_contiguous_frames(3), Err(FrameAllocError::InvalidRange));
+        assert!(allocator.allocator.used.iter().all(|&used| !used));
+        assert_eq!(allocator.allocate_contiguous_frames(2), Ok(Frame::from_number(0)));
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.allocator.used, vec![true, true, false, false, false, false]);
+    }
+
+    #[test]
+    fn shared_bitmap_frame_is_freed_by_second_deallocation() {
+        let mut bitmap = vec![0; 1];
+        let mut reserved = vec![0; 1];
+        let zero = PhysicalAddress::new(0);
+        let mut bitmap_allocator = BitmapFrameAllocator::from_areas(&mut bitmap, &mut reserved, zero, zero, zero, zero,
+                                                                    vec![(0, 16 * PAGE_SIZE)].into_iter()).unwrap();
+        let free_frames = bitmap_allocator.free_frame_count();
+        let mut counts = vec![0; 64];
+        {
+            let mut allocator = RefCountedAllocator::new(&mut bitmap_allocator, FrameRefCounter::new(&mut counts));
+            let frame = allocator.allocate_frame().unwrap();
+            allocator.share(&frame);
+
+            allocator.deallocate_frame(Frame::from_number(frame.number()));
+            assert_eq!(allocator.allocator.free_frame_count(), free_frames - 1);
+            allocator.deallocate_frame(frame);
+        }
+        assert_eq!(bitmap_allocator.free_frame_count(), free_frames);
+    }
+}
@@ -0,0 +1,248 @@
+use core::ptr;
+
+use memory::paging::PAGE_SIZE;
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+/// Number of frames a lazy `ScrubbingAllocator` queues before scrubbing them
+const SCRUB_QUEUE_SIZE: usize = 64;
+
+/// Writes to physical frames, which are not necessarily mapped
+pub trait FrameAccess {
+    /// Sets every byte of the frame to `byte`
+    fn fill(&mut self, frame: &Frame, byte: u8);
+}
+
+impl<F: FnMut(&Frame, u8)> FrameAccess for F {
+    fn fill(&mut self, frame: &Frame, byte: u8) {
+        self(frame, byte)
+    }
+}
+
+/// Writes to frames through a mapping of all physical memory starting at
+/// a fixed virtual address, which is 0 while memory is identity mapped
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetMapping {
+    physical_memory_offset: usize,
+}
+
+impl OffsetMapping {
+    /// Unsafe because every frame which is filled has to be mapped at
+    /// `physical_memory_offset` plus its start address
+    pub unsafe fn new(physical_memory_offset: usize) -> OffsetMapping {
+        OffsetMapping { physical_memory_offset: physical_memory_offset }
+    }
+}
+
+impl FrameAccess for OffsetMapping {
+    fn fill(&mut self, frame: &Frame, byte: u8) {
+        unsafe {
+            ptr::write_bytes(frame.as_ptr_offset(self.physical_memory_offset), byte, PAGE_SIZE);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrubPattern {
+    Zero,
+    /// 0xDE, which makes use after free easy to spot
+    Poison,
+}
+
+impl ScrubPattern {
+    fn byte(&self) -> u8 {
+        match *self {
+            ScrubPattern::Zero => 0,
+            ScrubPattern::Poison => 0xde,
+        }
+    }
+}
+
+/// Wraps an allocator and overwrites frames before they are freed.
+/// In lazy mode freed frames are queued and only scrubbed and handed back
+/// to the inner allocator by `scrub_pending`, when the queue is full or
+/// when the `ScrubbingAllocator` is dropped.
+pub struct ScrubbingAllocator<A: FrameDeallocator, F: FrameAccess> {
+    allocator: A,
+    access: F,
+    pattern: ScrubPattern,
+    lazy: bool,
+    pending: [usize; SCRUB_QUEUE_SIZE],
+    pending_count: usize,
+}
+
+impl<A: FrameDeallocator, F: FrameAccess> ScrubbingAllocator<A, F> {
+    pub fn new(allocator: A, access: F, pattern: ScrubPattern) -> ScrubbingAllocator<A, F> {
+        ScrubbingAllocator {
+            allocator: allocator,
+            access: access,
+            pattern: pattern,
+            lazy: false,
+            pending: [0; SCRUB_QUEUE_SIZE],
+            pending_count: 0,
+        }
+    }
+
+    /// Leaving lazy mode scrubs every queued frame
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+        if !lazy {
+            self.scrub_pending();
+        }
+    }
+
+    /// Number of freed frames which are not scrubbed yet
+    pub fn pending(&self) -> usize {
+        self.pending_count
+    }
+
+    /// Scrubs every queued frame and frees it in the inner allocator
+    pub fn scrub_pending(&mut self) {
+        for i in 0..self.pending_count {
+            self.scrub_and_free(Frame::from_number(self.pending[i]));
+        }
+        self.pending_count = 0;
+    }
+
+    fn scrub_and_free(&mut self, frame: Frame) {
+        self.access.fill(&frame, self.pattern.byte());
+        self.allocator.deallocate_frame(frame);
+    }
+}
+
+impl<A: FrameAllocator + FrameDeallocator, F: FrameAccess> FrameAllocator for ScrubbingAllocator<A, F> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.allocate_frame()
+    }
+
+    fn try_allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        self.allocator.try_allocate_frame()
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        self.allocator.allocate_contiguous_frames(count)
+    }
+}
+
+impl<A: FrameDeallocator, F: FrameAccess> FrameDeallocator for ScrubbingAllocator<A, F> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if self.lazy {
+            if self.pending_count == SCRUB_QUEUE_SIZE {
+                self.scrub_pending();
+            }
+            self.pending[self.pending_count] = frame.number();
+            self.pending_count += 1;
+        } else {
+            self.scrub_and_free(frame);
+        }
+    }
+}
+
+/// Queued frames would never be freed otherwise
+impl<A: FrameDeallocator, F: FrameAccess> Drop for ScrubbingAllocator<A, F> {
+    fn drop(&mut self) {
+        self.scrub_pending();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::MockAllocator;
+    use memory::paging::PAGE_SIZE;
+    use std::vec::Vec;
+
+    /// Physical memory seen through a fake identity mapping
+    struct FakeMemory {
+        bytes: Vec<u8>,
+    }
+
+    impl FrameAccess for FakeMemory {
+        fn fill(&mut self, frame: &Frame, byte: u8) {
+            for b in &mut self.bytes[frame.start_address()..frame.start_address() + PAGE_SIZE] {
+                *b = byte;
+            }
+        }
+    }
+
+    fn scrubbing_allocator(pattern: ScrubPattern) -> ScrubbingAllocator<MockAllocator, FakeMemory> {
+        ScrubbingAllocator::new(MockAllocator::new(4),
+                                FakeMemory{ bytes: vec![0x55; 4 * PAGE_SIZE] }, pattern)
+    }
+
+    fn frame_bytes<'a, A: FrameDeallocator>(allocator: &'a ScrubbingAllocator<A, FakeMemory>, frame_number: usize) -> &'a [u8] {
+        &allocator.access.bytes[frame_number * PAGE_SIZE..(frame_number + 1) * PAGE_SIZE]
+    }
+
+    #[test]
+    fn freed_frames_are_scrubbed() {
+        let mut allocator = scrubbing_allocator(ScrubPattern::Poison);
+        let frame = allocator.allocate_frame().unwrap();
+        let _ = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(frame);
+        assert!(frame_bytes(&allocator, 0).iter().all(|&byte| byte == 0xde));
+        assert!(frame_bytes(&allocator, 1).iter().all(|&byte| byte == 0x55));
+        assert_eq!(allocator.allocator.freed, vec![0]);
+    }
+
+    #[test]
+    fn lazy_mode_scrubs_in_batches() {
+        let mut allocator = scrubbing_allocator(ScrubPattern::Zero);
+        allocator.set_lazy(true);
+        let frame1 = allocator.allocate_frame().unwrap();
+        let frame2 = allocator.allocate_frame().unwrap();
+
+        allocator.deallocate_frame(frame1);
+        allocator.deallocate_frame(frame2);
+        assert_eq!(allocator.pending(), 2);
+        assert!(frame_bytes(&allocator, 0).iter().all(|&byte| byte == 0x55));
+        assert!(allocator.allocator.freed.is_empty());
+
+        allocator.scrub_pending();
+        assert_eq!(allocator.pending(), 0);
+        assert!(frame_bytes(&allocator, 0).iter().chain(frame_bytes(&allocator, 1)).all(|&byte| byte == 0));
+        assert_eq!(allocator.allocator.freed, vec![0, 1]);
+    }
+
+    #[test]
+    fn full_queue_is_scrubbed() {
+        let mut allocator = ScrubbingAllocator::new(MockAllocator::new(4),
+                                                    |_: &Frame, _: u8| {}, ScrubPattern::Zero);
+        allocator.set_lazy(true);
+        for frame_number in 0..SCRUB_QUEUE_SIZE + 1 {
+            allocator.deallocate_frame(Frame::from_number(frame_number));
+        }
+        assert_eq!(allocator.pending(), 1);
+        assert_eq!(allocator.allocator.freed.len(), SCRUB_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn dropping_scrubs_pending_frames() {
+        let mut inner = MockAllocator::new(4);
+        let mut scrubbed = Vec::new();
+        {
+            let mut allocator = ScrubbingAllocator::new(&mut inner, |frame: &Frame, _: u8| scrubbed.push(frame.number()),
+                                                        ScrubPattern::Zero);
+            allocator.set_lazy(true);
+            allocator.deallocate_frame(Frame::from_number(3));
+            allocator.deallocate_frame(Frame::from_number(7));
+            assert_eq!(allocator.pending(), 2);
+        }
+        assert_eq!(scrubbed, vec![3, 7]);
+        assert_eq!(inner.freed, vec![3, 7]);
+    }
+
+    #[test]
+    fn offset_mapping_scrubs_fake_physical_memory() {
+        let mut memory = vec![0x55u8; 3 * PAGE_SIZE];
+        let access = unsafe { OffsetMapping::new(memory.as_mut_ptr() as usize) };
+        {
+            let mut allocator = ScrubbingAllocator::new(MockAllocator::new(4),
+                                                        access, ScrubPattern::Poison);
+            allocator.deallocate_frame(Frame::from_number(1));
+        }
+        assert!(memory[..PAGE_SIZE].iter().all(|&byte| byte == 0x55));
+        assert!(memory[PAGE_SIZE..2 * PAGE_SIZE].iter().all(|&byte| byte == 0xde));
+        assert!(memory[2 * PAGE_SIZE..].iter().all(|&byte| byte == 0x55));
+    }
+}
@@ -40,8 +40,8 @@ impl StackAllocator {
                 }
 
                 // create a new stack
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+                let top_of_stack = end.start_address().as_usize() + PAGE_SIZE;
+                Some(Stack::new(top_of_stack, start.start_address().as_usize()))
             }
             _ => None, /* not enough pages */
         }
@@ -0,0 +1,182 @@
+use super::{Frame, FrameAllocator, FrameDeallocator, FrameAllocError};
+
+pub const OWNER_COUNT: usize = 5;
+
+/// Subsystem a frame was allocated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOwner {
+    PageTables,
+    Heap,
+    Stacks,
+    Dma,
+    Other,
+}
+
+impl MemOwner {
+    fn from_index(index: usize) -> MemOwner {
+        match index {
+            0 => MemOwner::PageTables,
+            1 => MemOwner::Heap,
+            2 => MemOwner::Stacks,
+            3 => MemOwner::Dma,
+            _ => MemOwner::Other,
+        }
+    }
+}
+
+/// Wraps an allocator and counts the frames allocated by each owner.
+/// The owner of every frame is kept in a side table with one byte per frame,
+/// frames beyond the end of the table are counted but not checked on free.
+pub struct TaggedFrameAllocator<'a, A> {
+    allocator: A,
+    owners: &'a mut [u8],
+    usage: [usize; OWNER_COUNT],
+}
+
+impl<'a, A: FrameAllocator> TaggedFrameAllocator<'a, A> {
+    pub fn new(allocator: A, owners: &'a mut [u8]) -> TaggedFrameAllocator<'a, A> {
+        for owner in owners.iter_mut() {
+            *owner = 0;
+        }
+        TaggedFrameAllocator {
+            allocator: allocator,
+            owners: owners,
+            usage: [0; OWNER_COUNT],
+        }
+    }
+
+    pub fn allocate_frame_tagged(&mut self, owner: MemOwner) -> Option<Frame> {
+        let frame = self.allocator.allocate_frame()?;
+        self.tag(frame.number(), owner);
+        Some(frame)
+    }
+
+    /// Number of frames currently allocated by each owner, indexed by `MemOwner as usize`
+    pub fn usage_by_owner(&self) -> [usize; OWNER_COUNT] {
+        self.usage
+    }
+
+    /// Owner recorded for the frame, if the side table covers it and it is allocated
+    pub fn owner_of(&self, frame: &Frame) -> Option<MemOwner> {
+        match self.owners.get(frame.number()) {
+            Some(&tag) if tag != 0 => Some(MemOwner::from_index(tag as usize - 1)),
+            _ => None,
+        }
+    }
+
+    fn tag(&mut self, frame_number: usize, owner: MemOwner) {
+        if let Some(tag) = self.owners.get_mut(frame_number) {
+            *tag = owner as u8 + 1;
+        }
+        self.usage[owner as usize] += 1;
+    }
+}
+
+impl<'a, A: FrameAllocator + FrameDeallocator> TaggedFrameAllocator<'a, A> {
+    /// Frees a frame allocated by `owner`. The frame is accounted to the owner
+    /// in the side table, `owner` is only used for frames beyond its end.
+    pub fn deallocate_frame_tagged(&mut self, frame: Frame, owner: MemOwner) {
+        let recorded = self.owner_of(&frame);
+        if let Some(recorded) = recorded {
+            debug_assert!(recorded == owner, "frame {} belongs to {:?}, not {:?}", frame.number(), recorded, owner);
+        }
+        let owner = recorded.unwrap_or(owner);
+        if let Some(tag) = self.owners.get_mut(frame.number()) {
+            *tag = 0;
+        }
+        self.usage[owner as usize] = self.usage[owner as usize].checked_sub(1)
+            .expect("more frames freed than allocated");
+        self.allocator.deallocate_frame(frame);
+    }
+}
+
+/// Untagged allocations are accounted to `MemOwner::Other`
+impl<'a, A: FrameAllocator> FrameAllocator for TaggedFrameAllocator<'a, A> {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_frame_tagged(MemOwner::Other)
+    }
+
+    fn allocate_contiguous_frames(&mut self, count: usize) -> Result<Frame, FrameAllocError> {
+        let frame = self.allocator.allocate_contiguous_frames(count)?;
+        for frame_number in frame.number()..frame.number() + count {
+            self.tag(frame_number, MemOwner::Other);
+        }
+        Ok(frame)
+    }
+}
+
+/// Frees a frame on behalf of the owner recorded in the side table
+impl<'a, A: FrameAllocator + FrameDeallocator> FrameDeallocator for TaggedFrameAllocator<'a, A> {
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let owner = self.owner_of(&frame).unwrap_or(MemOwner::Other);
+        self.deallocate_frame_tagged(frame, owner);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memory::MockAllocator;
+
+    #[test]
+    fn usage_is_counted_per_owner() {
+        let mut owners = vec![0xff; 16];
+        let mut allocator = TaggedFrameAllocator::new(MockAllocator::new(16), &mut owners);
+
+        let table = allocator.allocate_frame_tagged(MemOwner::PageTables).unwrap();
+        let heap1 = allocator.allocate_frame_tagged(MemOwner::Heap).unwrap();
+        let heap2 = allocator.allocate_frame_tagged(MemOwner::Heap).unwrap();
+        let other = allocator.allocate_contiguous_frames(2).unwrap();
+        assert_eq!(allocator.usage_by_owner(), [1, 2, 0, 0, 2]);
+        assert_eq!(allocator.owner_of(&heap1), Some(MemOwner::Heap));
+
+        allocator.deallocate_frame_tagged(heap1, MemOwner::Heap);
+        allocator.deallocate_frame(table);
+        allocator.deallocate_contiguous_frames(other, 2);
+        assert_eq!(allocator.usage_by_owner(), [0, 1, 0, 0, 0]);
+        assert_eq!(allocator.owner_of(&heap2), Some(MemOwner::Heap));
+        assert_eq!(allocator.allocator.freed, vec![1, 0, 3, 4]);
+    }
+
+    #[test]
+    fn frames_beyond_side_table_are_counted() {
+        let mut owners = vec![0; 1];
+        let mut allocator = TaggedFrameAllocator::new(MockAllocator::from_frame(5, 16), &mut owners);
+
+        let frame = allocator.allocate_frame_tagged(MemOwner::Dma).unwrap();
+        assert_eq!(allocator.owner_of(&frame), None);
+        assert_eq!(allocator.usage_by_owner()[MemOwner::Dma as usize], 1);
+        allocator.deallocate_frame_tagged(frame, MemOwner::Dma);
+        assert_eq!(allocator.usage_by_owner()[MemOwner::Dma as usize], 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame 0 belongs to Stacks, not Heap")]
+    fn owner_mismatch_is_detected() {
+        let mut owners = vec![0; 16];
+        let mut allocator = TaggedFrameAllocator::new(MockAllocator::new(16), &mut owners);
+
+        let frame = allocator.allocate_frame_tagged(MemOwner::Stacks).unwrap();
+        allocator.deallocate_frame_tagged(frame, MemOwner::Heap);
+    }
+
+    #[test]
+    fn recorded_owner_is_charged_on_free() {
+        let mut owners = vec![0; 16];
+        let mut allocator = TaggedFrameAllocator::new(MockAllocator::new(16), &mut owners);
+
+        let frame = allocator.allocate_frame_tagged(MemOwner::Stacks).unwrap();
+        allocator.allocate_frame_tagged(MemOwner::Heap).unwrap();
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.usage_by_owner(), [0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "more frames freed than allocated")]
+    fn freeing_untracked_frames_does_not_underflow() {
+        let mut owners = vec![0; 1];
+        let mut allocator = TaggedFrameAllocator::new(MockAllocator::from_frame(5, 16), &mut owners);
+
+        allocator.deallocate_frame_tagged(Frame::from_number(5), MemOwner::Dma);
+    }
+}